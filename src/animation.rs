@@ -0,0 +1,75 @@
+use fugit::ExtU64;
+
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+use crate::config::animation::Animation;
+use crate::BUTTON_COUNT;
+
+#[derive(Clone, Copy)]
+struct Playback {
+    frame: u16,
+    frame_count: u16,
+    // None for buttons that are not animated
+    next_at: Option<Micros>,
+}
+
+pub struct Animator<'a> {
+    timer: &'a dyn Monotonic,
+    playbacks: [Playback; BUTTON_COUNT],
+}
+
+impl<'a> Animator<'a> {
+    pub fn new(timer: &'a dyn Monotonic) -> Self {
+        Self {
+            timer,
+            playbacks: [Playback {
+                frame: 0,
+                frame_count: 0,
+                next_at: None,
+            }; BUTTON_COUNT],
+        }
+    }
+
+    // starts every animation of a freshly loaded page at its first frame
+    pub fn start(&mut self, animations: &[Animation; BUTTON_COUNT]) {
+        let now = self.timer.now();
+        for (playback, animation) in self.playbacks.iter_mut().zip(animations) {
+            *playback = Playback {
+                frame: 0,
+                frame_count: animation.frame_count,
+                next_at: animation.is_animated().then_some(now),
+            };
+        }
+    }
+
+    pub fn is_playing(&self, index: usize) -> bool {
+        self.playbacks[index].next_at.is_some()
+    }
+
+    pub fn stop(&mut self, index: usize) {
+        self.playbacks[index].next_at = None;
+    }
+
+    // continues with the frame it stopped at, static images stay stopped
+    pub fn resume(&mut self, index: usize) {
+        let playback = &mut self.playbacks[index];
+        if playback.frame_count > 1 {
+            playback.next_at = Some(self.timer.now());
+        }
+    }
+
+    // the first button whose next frame is due, with that frame
+    pub fn due(&self) -> Option<(usize, u16)> {
+        let now = self.timer.now();
+        self.playbacks
+            .iter()
+            .position(|playback| matches!(playback.next_at, Some(at) if at <= now))
+            .map(|index| (index, self.playbacks[index].frame))
+    }
+
+    pub fn shown(&mut self, index: usize, duration_ms: u16) {
+        let playback = &mut self.playbacks[index];
+        playback.frame = (playback.frame + 1) % playback.frame_count;
+        playback.next_at = Some(self.timer.now() + (duration_ms as u64).millis());
+    }
+}
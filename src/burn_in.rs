@@ -0,0 +1,81 @@
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+use crate::render::Shift;
+use crate::render::NO_SHIFT;
+
+// walks around the original position one pixel at a time
+const ORBIT: [Shift; 9] = [
+    NO_SHIFT,
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+const INVERT_MS: u64 = 2000;
+
+pub enum BurnInChange {
+    Shift,
+    Invert(bool),
+}
+
+pub struct BurnIn<'a> {
+    timer: &'a dyn Monotonic,
+    shift_interval: u64,
+    invert_interval: u64,
+    step: usize,
+    last_shift: Micros,
+    last_invert: Micros,
+    inverted: bool,
+}
+
+impl<'a> BurnIn<'a> {
+    // an interval of 0 turns that mitigation off
+    pub fn new(timer: &'a dyn Monotonic, shift_interval: u64, invert_interval: u64) -> Self {
+        Self {
+            timer,
+            shift_interval,
+            invert_interval,
+            step: 0,
+            last_shift: timer.now(),
+            last_invert: timer.now(),
+            inverted: false,
+        }
+    }
+
+    pub fn shift(&self) -> Shift {
+        ORBIT[self.step]
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    fn since(&self, then: Micros) -> u64 {
+        match self.timer.now().checked_duration_since(then) {
+            Some(elapsed) => elapsed.to_millis(),
+            None => 0,
+        }
+    }
+
+    pub fn update(&mut self) -> Option<BurnInChange> {
+        if self.inverted && self.since(self.last_invert) >= INVERT_MS {
+            self.inverted = false;
+            return Some(BurnInChange::Invert(false));
+        }
+        if self.invert_interval != 0 && self.since(self.last_invert) >= self.invert_interval {
+            self.inverted = true;
+            self.last_invert = self.timer.now();
+            return Some(BurnInChange::Invert(true));
+        }
+        if self.shift_interval != 0 && self.since(self.last_shift) >= self.shift_interval {
+            self.step = (self.step + 1) % ORBIT.len();
+            self.last_shift = self.timer.now();
+            return Some(BurnInChange::Shift);
+        }
+        None
+    }
+}
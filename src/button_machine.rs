@@ -1,219 +1,409 @@
-pub use button_machine::*;
 use defmt::Format;
 use embedded_hal::digital::v2::InputPin;
-use finite_state_machine::state_machine;
 
-use crate::config::button::Button;
-use crate::debug;
+use crate::monotonic::Micros;
+use crate::monotonic::Monotonic;
 
-use super::hal;
-use fugit::Instant;
-use hal::gpio::DynPin;
-
-#[derive(Format)]
+#[derive(Format, Debug, PartialEq, Eq)]
 pub enum ButtonEvent {
     ShortDown,
     ShortUp,
     ShortTriggered,
     LongTriggered,
+    LongProgress(u8),
+    Pressed,
+    Released,
+    Wake,
 }
 
-pub struct Data<'a> {
-    down_at: Option<Instant<u64, 1, 1000000>>,
-    has_second_function: bool,
-    execute: &'a mut dyn FnMut(Option<ButtonEvent>),
+#[derive(Clone, Copy)]
+pub struct PressOptions {
+    pub has_second_function: bool,
+    // only without a second function, a press cannot fire before it is known not to be long
+    pub trigger_on_press: bool,
+    pub long_press_duration: Option<u64>,
+    pub wake_only: bool,
+}
+
+pub trait ButtonInput {
+    fn is_down(&self) -> bool;
 }
 
-pub struct Config<'a> {
-    pin: &'a DynPin,
+impl<P: InputPin> ButtonInput for P {
+    fn is_down(&self) -> bool {
+        self.is_low().unwrap_or(false)
+    }
+}
+
+// what is known about the press of the scanned button
+struct Press {
+    down_at: Micros,
+    has_second_function: bool,
+    trigger_on_press: bool,
     long_press_duration: u64,
-    timer: &'a hal::Timer,
+    progress: u8,
+    wake_only: bool,
 }
 
-state_machine!(
-    ButtonMachine(Config<'a>, Data<'b>);
-    Start {
-        IsUp => End,
-        IsDown => Down
-    },
-    Down {
-        StillDown => Down,
-        AnyUp => Up,
-        HeldLong => DownButWaiting
-    },
-    DownButWaiting {
-        StillDown => DownButWaiting,
-        Released => Up
-    },
-    Up {
-        UpAfterLong => End,
-        ShortUp => End,
-        ShortHeld => End
-    }
-);
-
-enum ButtonState {
+impl Press {
+    // a press that wakes the screens must not trigger anything else
+    fn emit(&self, execute: &mut dyn FnMut(Option<ButtonEvent>), event: ButtonEvent) {
+        if !self.wake_only {
+            execute(Some(event));
+        }
+    }
+}
+
+enum State {
     Up,
-    Down,
+    Down(Press),
+    // the long press fired, only the release is left
+    DownButWaiting(Press),
 }
 
-impl<'a> ButtonMachine<'a> {
-    pub fn new(
-        pin: &'a DynPin,
-        long_press_duration: u64,
-        timer: &'a hal::Timer,
-    ) -> ButtonMachine<'a> {
-        ButtonMachine {
-            config: Config {
-                pin,
-                long_press_duration,
-                timer,
-            },
-        }
-    }
-    fn get_button_state(&self) -> ButtonState {
-        let is_down = match self.config.pin.is_low() {
-            Ok(state) => state,
-            Err(_) => {
-                return ButtonState::Up;
-            }
-        };
+pub struct ButtonMachine<'a, P, M> {
+    pin: &'a P,
+    long_press_duration: u64,
+    timer: &'a M,
+    state: State,
+}
 
-        if is_down {
-            ButtonState::Down
-        } else {
-            ButtonState::Up
+impl<'a, P, M> ButtonMachine<'a, P, M>
+where
+    P: InputPin,
+    M: Monotonic,
+{
+    pub fn new(pin: &'a P, long_press_duration: u64, timer: &'a M) -> Self {
+        ButtonMachine {
+            pin,
+            long_press_duration,
+            timer,
+            state: State::Up,
         }
     }
-    fn get_now(&self) -> Instant<u64, 1, 1000000> {
-        Instant::<u64, 1, 1_000_000>::from_ticks(self.config.timer.get_counter().ticks())
+    fn get_now(&self) -> Micros {
+        self.timer.now()
     }
-    fn get_diff_since_down(&self, data: &Data) -> u64 {
-        let down_at = data.down_at.unwrap();
+    fn get_diff_since_down(&self, press: &Press) -> u64 {
         let now = self.get_now();
-        now.checked_duration_since(down_at).unwrap().to_millis()
+        now.checked_duration_since(press.down_at)
+            .unwrap()
+            .to_millis()
+    }
+    fn get_progress(&self, press: &Press) -> u8 {
+        let diff = self.get_diff_since_down(press);
+        let duration = press.long_press_duration.max(1);
+        (diff.min(duration) * 100 / duration) as u8
     }
-    pub fn check_button<'b>(
+    fn start(&self, options: PressOptions, execute: &mut dyn FnMut(Option<ButtonEvent>)) -> State {
+        if !self.pin.is_down() {
+            execute(None);
+            return State::Up;
+        }
+        let press = Press {
+            down_at: self.get_now(),
+            has_second_function: options.has_second_function,
+            trigger_on_press: options.trigger_on_press,
+            long_press_duration: options
+                .long_press_duration
+                .unwrap_or(self.long_press_duration),
+            progress: 0,
+            wake_only: options.wake_only,
+        };
+        if press.wake_only {
+            execute(Some(ButtonEvent::Wake));
+        }
+        press.emit(execute, ButtonEvent::Pressed);
+        match press.has_second_function {
+            false if press.trigger_on_press => press.emit(execute, ButtonEvent::ShortTriggered),
+            false => press.emit(execute, ButtonEvent::ShortDown),
+            true => {}
+        }
+        State::Down(press)
+    }
+    fn down(&self, mut press: Press, execute: &mut dyn FnMut(Option<ButtonEvent>)) -> State {
+        if press.has_second_function && self.get_diff_since_down(&press) > press.long_press_duration
+        {
+            press.emit(execute, ButtonEvent::LongTriggered);
+            return State::DownButWaiting(press);
+        }
+        if !self.pin.is_down() {
+            press.emit(execute, ButtonEvent::Released);
+            match press.has_second_function {
+                true => press.emit(execute, ButtonEvent::ShortTriggered),
+                false if !press.trigger_on_press => press.emit(execute, ButtonEvent::ShortUp),
+                false => {}
+            }
+            return State::Up;
+        }
+        if press.has_second_function {
+            let progress = self.get_progress(&press);
+            if progress != press.progress {
+                press.progress = progress;
+                press.emit(execute, ButtonEvent::LongProgress(progress));
+            }
+        }
+        State::Down(press)
+    }
+    fn down_but_waiting(
+        &self,
+        press: Press,
+        execute: &mut dyn FnMut(Option<ButtonEvent>),
+    ) -> State {
+        if !self.pin.is_down() {
+            press.emit(execute, ButtonEvent::Released);
+            return State::Up;
+        }
+        State::DownButWaiting(press)
+    }
+    // reads the pin once and returns, a press goes on over the following calls,
+    // the options are taken when it starts
+    pub fn check_button(
         &mut self,
-        has_second_function: bool,
+        options: PressOptions,
         execute: &mut dyn FnMut(Option<ButtonEvent>),
-    ) -> Result<(), &'static str> {
-        let mut state = Data {
-            down_at: None,
-            has_second_function,
-            execute,
+    ) {
+        self.state = match core::mem::replace(&mut self.state, State::Up) {
+            State::Up => self.start(options, execute),
+            State::Down(press) => self.down(press, execute),
+            State::DownButWaiting(press) => self.down_but_waiting(press, execute),
         };
-        self.run_to_end(&mut state)?;
-        Ok(())
+    }
+    pub fn is_pressed(&self) -> bool {
+        !matches!(self.state, State::Up)
     }
 }
 
-impl<'a, 'b> Deciders<Data<'b>> for ButtonMachine<'a> {
-    fn start(&self, data: &Data) -> StartEvents {
-        match self.get_button_state() {
-            ButtonState::Down => StartEvents::IsDown,
-            ButtonState::Up => StartEvents::IsUp,
-        }
-    }
-    fn down(&self, data: &Data) -> DownEvents {
-        // todo: implement wakeup from screensaver
-        match data.has_second_function {
-            true => {
-                let diff = self.get_diff_since_down(data);
-                if diff > self.config.long_press_duration {
-                    return DownEvents::HeldLong;
-                }
-                match self.get_button_state() {
-                    ButtonState::Down => DownEvents::StillDown,
-                    ButtonState::Up => DownEvents::AnyUp,
-                }
-            }
-            false => match self.get_button_state() {
-                ButtonState::Down => DownEvents::StillDown,
-                ButtonState::Up => DownEvents::AnyUp,
-            },
-        }
-    }
-    fn up(&self, data: &Data) -> UpEvents {
-        match data.has_second_function {
-            true => {
-                let diff = self.get_diff_since_down(data);
-                if diff > self.config.long_press_duration {
-                    return UpEvents::UpAfterLong;
-                }
-                UpEvents::ShortHeld
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use embedded_hal::digital::v2::InputPin;
+
+    use super::*;
+
+    const LONG_PRESS_MS: u64 = 200;
+
+    // every scan of the pin takes a millisecond, the button is down until released_at
+    struct Timeline {
+        now_ms: Cell<u64>,
+        released_at: u64,
+    }
+
+    impl Timeline {
+        fn new(released_at: u64) -> Self {
+            Self {
+                now_ms: Cell::new(0),
+                released_at,
             }
-            false => UpEvents::ShortUp,
+        }
+
+        fn scan(&self) -> bool {
+            let now = self.now_ms.get();
+            self.now_ms.set(now + 1);
+            now < self.released_at
         }
     }
-    fn down_but_waiting(&self, data: &Data) -> DownButWaitingEvents {
-        match self.get_button_state() {
-            ButtonState::Down => DownButWaitingEvents::StillDown,
-            ButtonState::Up => DownButWaitingEvents::Released,
+
+    impl InputPin for Timeline {
+        type Error = Infallible;
+        fn is_high(&self) -> Result<bool, Infallible> {
+            Ok(!self.scan())
+        }
+        fn is_low(&self) -> Result<bool, Infallible> {
+            Ok(self.scan())
         }
     }
-}
 
-impl<'a, 'b> StartTransitions<Data<'b>> for ButtonMachine<'a> {
-    fn illegal(&mut self) {}
-    fn is_down(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        data.down_at = Some(self.get_now());
-        match data.has_second_function {
-            false => {
-                (data.execute)(Some(ButtonEvent::ShortDown));
-            }
-            true => {}
+    impl Monotonic for Timeline {
+        fn now(&self) -> Micros {
+            Micros::from_ticks(self.now_ms.get() * 1000)
         }
-        Ok(())
     }
-    fn is_up(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        (data.execute)(None);
-        Ok(())
+
+    fn options(has_second_function: bool, trigger_on_press: bool) -> PressOptions {
+        PressOptions {
+            has_second_function,
+            trigger_on_press,
+            long_press_duration: None,
+            wake_only: false,
+        }
+    }
+
+    fn press(options: PressOptions, held_ms: u64) -> Vec<ButtonEvent> {
+        let timeline = Timeline::new(held_ms);
+        let mut machine = ButtonMachine::new(&timeline, LONG_PRESS_MS, &timeline);
+        let mut events = Vec::new();
+        let mut record = |event| events.extend(event);
+        machine.check_button(options, &mut record);
+        while machine.is_pressed() {
+            machine.check_button(options, &mut record);
+        }
+        events
     }
-}
 
-impl<'a, 'b> DownTransitions<Data<'b>> for ButtonMachine<'a> {
-    fn illegal(&mut self) {}
-    fn held_long(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        // let start = Instant::<u64, 1, 1_000_000>::from_ticks(self.config.timer.get_counter().ticks());
-        (data.execute)(Some(ButtonEvent::LongTriggered));
-        // let end = Instant::<u64, 1, 1_000_000>::from_ticks(self.config.timer.get_counter().ticks());
-        // let diff = (end - start).to_micros();
-        // debug!("long press took {}us", diff);
-        Ok(())
+    fn progress(last: u8) -> impl Iterator<Item = ButtonEvent> {
+        (1..=last).map(ButtonEvent::LongProgress)
     }
-    fn any_up(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        Ok(())
+
+    fn sequence(
+        start: impl IntoIterator<Item = ButtonEvent>,
+        progress: impl Iterator<Item = ButtonEvent>,
+        end: impl IntoIterator<Item = ButtonEvent>,
+    ) -> Vec<ButtonEvent> {
+        start.into_iter().chain(progress).chain(end).collect()
     }
-    fn still_down(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        Ok(())
+
+    #[test]
+    fn check_returns_after_a_scan() {
+        let timeline = Timeline::new(300);
+        let mut machine = ButtonMachine::new(&timeline, LONG_PRESS_MS, &timeline);
+        let mut events = Vec::new();
+        machine.check_button(options(true, false), &mut |event| events.extend(event));
+        assert_eq!(events, [ButtonEvent::Pressed]);
+        assert_eq!(timeline.now_ms.get(), 1);
+        assert!(machine.is_pressed());
     }
-}
 
-impl<'a, 'b> DownButWaitingTransitions<Data<'b>> for ButtonMachine<'a> {
-    fn illegal(&mut self) {}
-    fn released(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        Ok(())
+    #[test]
+    fn idle_scan_emits_nothing() {
+        assert!(press(options(true, false), 0).is_empty());
     }
-    fn still_down(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        Ok(())
+
+    #[test]
+    fn short_press_without_secondary_function() {
+        assert_eq!(
+            press(options(false, false), 50),
+            [
+                ButtonEvent::Pressed,
+                ButtonEvent::ShortDown,
+                ButtonEvent::Released,
+                ButtonEvent::ShortUp,
+            ]
+        );
     }
-}
 
-impl<'a, 'b> UpTransitions<Data<'b>> for ButtonMachine<'a> {
-    fn illegal(&mut self) {}
-    fn short_held(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        (data.execute)(Some(ButtonEvent::ShortTriggered));
-        data.down_at = None;
-        Ok(())
-    }
-    fn short_up(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        (data.execute)(Some(ButtonEvent::ShortUp));
-        data.down_at = None;
-        Ok(())
-    }
-    fn up_after_long(&mut self, data: &mut Data) -> Result<(), &'static str> {
-        data.down_at = None;
-        Ok(())
+    #[test]
+    fn short_press_triggered_on_press() {
+        assert_eq!(
+            press(options(false, true), 50),
+            [
+                ButtonEvent::Pressed,
+                ButtonEvent::ShortTriggered,
+                ButtonEvent::Released,
+            ]
+        );
+    }
+
+    #[test]
+    fn trigger_on_press_waits_for_secondary_function() {
+        assert_eq!(
+            press(options(true, true), 100),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(49),
+                [ButtonEvent::Released, ButtonEvent::ShortTriggered],
+            )
+        );
+    }
+
+    #[test]
+    fn long_press_without_secondary_function_stays_short() {
+        assert_eq!(
+            press(options(false, false), 500),
+            [
+                ButtonEvent::Pressed,
+                ButtonEvent::ShortDown,
+                ButtonEvent::Released,
+                ButtonEvent::ShortUp,
+            ]
+        );
+    }
+
+    #[test]
+    fn short_press_with_secondary_function() {
+        assert_eq!(
+            press(options(true, false), 100),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(49),
+                [ButtonEvent::Released, ButtonEvent::ShortTriggered],
+            )
+        );
+    }
+
+    #[test]
+    fn long_press_with_secondary_function() {
+        assert_eq!(
+            press(options(true, false), 300),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(100),
+                [ButtonEvent::LongTriggered, ButtonEvent::Released],
+            )
+        );
+    }
+
+    #[test]
+    fn release_at_threshold_is_short() {
+        assert_eq!(
+            press(options(true, false), LONG_PRESS_MS),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(99),
+                [ButtonEvent::Released, ButtonEvent::ShortTriggered],
+            )
+        );
+    }
+
+    // the threshold is passed, but the long press has not fired yet
+    #[test]
+    fn release_right_after_threshold_is_not_lost() {
+        assert_eq!(
+            press(options(true, false), LONG_PRESS_MS + 1),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(100),
+                [ButtonEvent::Released, ButtonEvent::ShortTriggered],
+            )
+        );
+    }
+
+    #[test]
+    fn long_press_fires_once_past_threshold() {
+        assert_eq!(
+            press(options(true, false), LONG_PRESS_MS + 2),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(100),
+                [ButtonEvent::LongTriggered, ButtonEvent::Released],
+            )
+        );
+    }
+
+    #[test]
+    fn button_long_press_duration_overrides_default() {
+        let options = PressOptions {
+            long_press_duration: Some(500),
+            ..options(true, false)
+        };
+        assert_eq!(
+            press(options, 300),
+            sequence(
+                [ButtonEvent::Pressed],
+                progress(59),
+                [ButtonEvent::Released, ButtonEvent::ShortTriggered],
+            )
+        );
+    }
+
+    #[test]
+    fn wake_press_only_wakes() {
+        let options = PressOptions {
+            wake_only: true,
+            ..options(true, false)
+        };
+        assert_eq!(press(options, 300), [ButtonEvent::Wake]);
     }
 }
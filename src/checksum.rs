@@ -0,0 +1,103 @@
+use core::ops::Range;
+
+// crc-32 as used by zip and zlib
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+    // data read from a file at the position, the bytes of the file in the range count as zero
+    pub fn update_zeroed(&mut self, data: &[u8], position: usize, zeroed: &Range<usize>) {
+        let start = zeroed.start.saturating_sub(position).min(data.len());
+        let end = zeroed.end.saturating_sub(position).min(data.len());
+        self.update(&data[..start]);
+        for _ in start..end {
+            self.update(&[0]);
+        }
+        self.update(&data[end..]);
+    }
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUM: Range<usize> = 20..24;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    fn file() -> Vec<u8> {
+        (0..1500).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    // the crc of the file read in chunks of the size
+    fn crc32_zeroed(file: &[u8], chunk: usize) -> u32 {
+        let mut crc = Crc32::new();
+        for (i, data) in file.chunks(chunk).enumerate() {
+            crc.update_zeroed(data, i * chunk, &CHECKSUM);
+        }
+        crc.finish()
+    }
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn split_updates() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn zeroed_field_is_read_as_zeros() {
+        let mut zeros_in_place = file();
+        zeros_in_place[CHECKSUM].fill(0);
+        let expected = crc32(&zeros_in_place);
+        assert_ne!(crc32(&file()), expected);
+        assert_eq!(crc32_zeroed(&file(), 512), expected);
+    }
+
+    #[test]
+    fn zeroed_field_split_across_chunks() {
+        let mut zeros_in_place = file();
+        zeros_in_place[CHECKSUM].fill(0);
+        let expected = crc32(&zeros_in_place);
+        for chunk in [1, 3, 7, 21, 22, 64] {
+            assert_eq!(crc32_zeroed(&file(), chunk), expected, "chunk {}", chunk);
+        }
+    }
+}
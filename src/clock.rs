@@ -0,0 +1,103 @@
+use core::cell::Cell;
+
+use embedded_sdmmc::TimeSource;
+use embedded_sdmmc::Timestamp;
+
+use freedeck::date_time::in_fat_range;
+use freedeck::date_time::FAT_FIRST_YEAR;
+use freedeck::date_time::FAT_LAST_YEAR;
+use freedeck::date_time::FAT_START;
+
+pub use freedeck::date_time::DateTime;
+pub use freedeck::monotonic::Micros;
+pub use freedeck::monotonic::Monotonic;
+
+use super::hal;
+use super::pac;
+use crate::debug;
+
+// the clock trait lives in the library, which does not know the hal
+pub struct SystemTimer(hal::Timer);
+
+impl SystemTimer {
+    pub fn new(timer: pac::TIMER, resets: &mut pac::RESETS) -> Self {
+        Self(hal::Timer::new(timer, resets))
+    }
+}
+
+impl Monotonic for SystemTimer {
+    fn now(&self) -> Micros {
+        Micros::from_ticks(self.0.get_counter().ticks())
+    }
+}
+
+// sent over serial, followed by the unix time in seconds u64 LE
+const SET_TIME_COMMAND: [u8; 4] = *b"FDTM";
+
+// wall clock time, counted on from the last time the host set it
+pub struct WallClock<'a> {
+    timer: &'a dyn Monotonic,
+    set: Cell<Option<(u64, Micros)>>,
+}
+
+impl<'a> WallClock<'a> {
+    pub fn new(timer: &'a dyn Monotonic) -> Self {
+        Self {
+            timer,
+            set: Cell::new(None),
+        }
+    }
+
+    pub fn set(&self, unix: u64) {
+        self.set.set(Some((unix, self.timer.now())));
+    }
+
+    // unix time in seconds, None until the host set it
+    pub fn now(&self) -> Option<u64> {
+        self.at(self.timer.now())
+    }
+
+    // unix time of another moment since boot
+    pub fn at(&self, instant: Micros) -> Option<u64> {
+        let (unix, set_at) = self.set.get()?;
+        match instant.checked_duration_since(set_at) {
+            Some(after) => Some(unix.saturating_add(after.to_secs())),
+            None => unix.checked_sub((set_at - instant).to_secs()),
+        }
+    }
+
+    pub fn date_time(&self) -> Option<DateTime> {
+        self.now().map(DateTime::from)
+    }
+
+    // true if data was a set time command
+    pub fn receive(&self, data: &[u8]) -> bool {
+        if data.len() < 12 || data[..4] != SET_TIME_COMMAND {
+            return false;
+        }
+        let unix = u64::from_le_bytes(data[4..12].try_into().unwrap());
+        if !in_fat_range(unix) {
+            debug!("time {} out of range, ignored", unix);
+            return true;
+        }
+        debug!("time set to {}", unix);
+        self.set(unix);
+        true
+    }
+}
+
+// files written before the host set the time are dated 1980
+impl TimeSource for &WallClock<'_> {
+    fn get_timestamp(&self) -> Timestamp {
+        let date_time = self.date_time().unwrap_or(DateTime::from(FAT_START));
+        let year = date_time.year.clamp(FAT_FIRST_YEAR, FAT_LAST_YEAR);
+        Timestamp {
+            year_since_1970: (year - 1970) as u8,
+            zero_indexed_month: date_time.month - 1,
+            zero_indexed_day: date_time.day - 1,
+            hours: date_time.hours,
+            minutes: date_time.minutes,
+            seconds: date_time.seconds,
+        }
+    }
+}
@@ -6,8 +6,8 @@ pub enum ButtonFunction<'a> {
     ChangePage(ChangePage),   //1
     None,                     //2
     PressSpecialKey,          //3
-    SendText,                 //4
-    SetSetting,               //5
+    SendText(SendText<'a>),   //4
+    SetSetting(Setting),      //5
     CommunicateToHost,        //6
 }
 #[derive(Debug)]
@@ -15,11 +15,14 @@ pub struct ChangePage {
     pub target_page: u16,
 }
 
-impl From<&[u8]> for ChangePage {
-    fn from(value: &[u8]) -> Self {
-        Self {
-            target_page: u16::from_le_bytes(value[0..2].try_into().unwrap()),
-        }
+impl TryFrom<&[u8]> for ChangePage {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let target_page = value.get(0..2).ok_or(())?;
+        Ok(Self {
+            target_page: u16::from_le_bytes([target_page[0], target_page[1]]),
+        })
     }
 }
 
@@ -29,15 +32,54 @@ pub struct PressKeys<'a> {
     pub goto: Option<u16>,
 }
 
-impl<'a> From<&'a [u8]> for PressKeys<'a> {
-    fn from(value: &'a [u8]) -> Self {
+// the keys are zero terminated, the goto page sits in front of the last byte
+impl<'a> TryFrom<&'a [u8]> for PressKeys<'a> {
+    type Error = ();
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
         let len = value.len();
-        let last_key_index = value.iter().position(|&k| k == 0).unwrap();
+        let goto_at = len.checked_sub(3).ok_or(())?;
+        let last_key_index = value[..goto_at].iter().position(|&k| k == 0).ok_or(())?;
         let keys = &value[..last_key_index];
-        let goto = match u16::from_le_bytes(value[len - 3..len - 1].try_into().unwrap()) {
+        let goto = match u16::from_le_bytes([value[goto_at], value[goto_at + 1]]) {
             0 => None,
             p => Some(p - 1),
         };
-        Self { keys, goto }
+        Ok(Self { keys, goto })
+    }
+}
+
+#[derive(Debug)]
+pub enum Setting {
+    DecreaseBrightness,
+    IncreaseBrightness,
+    SetBrightness(u8),
+    Unknown,
+}
+
+impl TryFrom<&[u8]> for Setting {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(match value.first().ok_or(())? {
+            0 => Setting::DecreaseBrightness,
+            1 => Setting::IncreaseBrightness,
+            2 => Setting::SetBrightness(*value.get(1).ok_or(())?),
+            _ => Setting::Unknown,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct SendText<'a> {
+    pub text: &'a [u8],
+}
+
+impl<'a> From<&'a [u8]> for SendText<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        let end = value.iter().position(|&c| c == 0).unwrap_or(value.len());
+        Self {
+            text: &value[..end],
+        }
     }
 }
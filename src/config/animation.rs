@@ -0,0 +1,28 @@
+// one entry per button: frames offset u32 LE, frame count u16 LE, 2 bytes reserved
+pub const ENTRY_SIZE: u32 = 8;
+// every frame: duration in ms u16 LE followed by the image pixels
+pub const FRAME_HEADER_SIZE: u32 = 2;
+// keeps the i2c bus free for the other displays
+pub const MIN_FRAME_MS: u16 = 20;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Animation {
+    pub offset: u32,
+    pub frame_count: u16,
+}
+
+impl From<[u8; ENTRY_SIZE as usize]> for Animation {
+    fn from(entry: [u8; ENTRY_SIZE as usize]) -> Self {
+        Self {
+            offset: u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]),
+            frame_count: u16::from_le_bytes([entry[4], entry[5]]),
+        }
+    }
+}
+
+impl Animation {
+    // a single frame is just a static image
+    pub fn is_animated(&self) -> bool {
+        self.frame_count > 1
+    }
+}
@@ -1,6 +1,8 @@
 use core::ops::Range;
 
 use super::action::ButtonFunction;
+use super::feedback::PressFeedback;
+use super::orientation::Orientation;
 use super::IMAGE_SIZE;
 use super::ROW_SIZE;
 
@@ -13,44 +15,90 @@ const PRIMARY_DATA: Range<usize> = 1..DATA_SIZE + 1;
 const SECONDARY_BYTE: usize = ROW_SIZE as usize / 2;
 const SECONDARY_DATA: Range<usize> = DATA_SIZE + 2..ROW_SIZE as usize;
 
+// upper nibble of the primary mode byte, trigger on press is ignored
+// while the secondary function is enabled
+const TRIGGER_ON_PRESS: u8 = 0b0001_0000;
+const DISABLE_SECONDARY: u8 = 0b0010_0000;
+
+// upper nibble of the secondary mode byte, 0 uses the global duration
+const LONG_PRESS_STEP_MS: u64 = 100;
+
+// image flag byte, bits 1-4 hold the orientation on top of the global one
+const LIVE_DATA: u8 = 0b0000_0001;
+const ORIENTATION_SHIFT: u8 = 1;
+// bits 5-6: 0 uses the global press feedback, otherwise feedback + 1
+const FEEDBACK_SHIFT: u8 = 5;
+const FEEDBACK_MASK: u8 = 0b11;
+// the image holds zero terminated label text instead of pixels
+const LABEL: u8 = 0b1000_0000;
+
+// malformed data is an error rather than a panic, the file comes from the host
+fn function(mode: u8, data: &[u8]) -> Result<ButtonFunction, ()> {
+    Ok(match mode % 16 {
+        0 => ButtonFunction::PressKeys(data.try_into()?),
+        1 => ButtonFunction::ChangePage(data.try_into()?),
+        3 => ButtonFunction::PressSpecialKey,
+        4 => ButtonFunction::SendText(data.into()),
+        5 => ButtonFunction::SetSetting(data.try_into()?),
+        6 => ButtonFunction::CommunicateToHost,
+        _ => ButtonFunction::None, // invalid but also 2
+    })
+}
+
 #[derive(Debug)]
 pub struct Button {
     pub raw_image: [u8; IMAGE_SIZE as usize],
     pub raw_data: [u8; ROW_SIZE as usize],
+    pub image_size: usize,
 }
 
 impl Button {
-    pub fn primary_function(&self) -> ButtonFunction {
-        match self.raw_data[PRIMARY_BYTE] % 16 {
-            0 => ButtonFunction::PressKeys(self.primary_data().into()),
-            1 => ButtonFunction::ChangePage(self.primary_data().into()),
-            3 => ButtonFunction::PressSpecialKey,
-            4 => ButtonFunction::SendText,
-            5 => ButtonFunction::SetSetting,
-            6 => ButtonFunction::CommunicateToHost,
-            _ => ButtonFunction::None, // invalid but also 2
-        }
+    pub fn primary_function(&self) -> Result<ButtonFunction, ()> {
+        function(self.raw_data[PRIMARY_BYTE], self.primary_data())
     }
-    pub fn secondary_function(&self) -> ButtonFunction {
-        match self.raw_data[SECONDARY_BYTE] % 16 {
-            0 => ButtonFunction::PressKeys(self.secondary_data().into()),
-            1 => ButtonFunction::ChangePage(self.secondary_data().into()),
-            3 => ButtonFunction::PressSpecialKey,
-            4 => ButtonFunction::SendText,
-            5 => ButtonFunction::SetSetting,
-            6 => ButtonFunction::CommunicateToHost,
-            _ => ButtonFunction::None, // invalid but also 2
-        }
+    pub fn secondary_function(&self) -> Result<ButtonFunction, ()> {
+        function(self.raw_data[SECONDARY_BYTE], self.secondary_data())
     }
     pub fn has_secondary_function(&self) -> bool {
-        self.raw_data[SECONDARY_BYTE] != 2
+        self.raw_data[PRIMARY_BYTE] & DISABLE_SECONDARY == 0
+            && self.raw_data[SECONDARY_BYTE] % 16 != 2
+    }
+    pub fn triggers_on_press(&self) -> bool {
+        self.raw_data[PRIMARY_BYTE] & TRIGGER_ON_PRESS != 0
+    }
+    pub fn long_press_duration(&self) -> Option<u64> {
+        match self.raw_data[SECONDARY_BYTE] >> 4 {
+            0 => None,
+            steps => Some(steps as u64 * LONG_PRESS_STEP_MS),
+        }
     }
     pub fn has_live_data(&self) -> bool {
-        self.raw_image[0] == 1
+        self.raw_image[0] & LIVE_DATA != 0
+    }
+    pub fn orientation(&self) -> Orientation {
+        Orientation::from(self.raw_image[0] >> ORIENTATION_SHIFT)
+    }
+
+    pub fn press_feedback(&self) -> Option<PressFeedback> {
+        match self.raw_image[0] >> FEEDBACK_SHIFT & FEEDBACK_MASK {
+            0 => None,
+            feedback => Some(PressFeedback::from(feedback - 1)),
+        }
+    }
+    pub fn has_image(&self) -> bool {
+        self.raw_image[0] & LABEL == 0 && self.image_buff().iter().any(|&b| b != 0)
+    }
+    pub fn label_text(&self) -> Option<&[u8]> {
+        if self.raw_image[0] & LABEL == 0 {
+            return None;
+        }
+        let text = self.image_buff();
+        let end = text.iter().position(|&c| c == 0).unwrap_or(text.len());
+        Some(&text[..end])
     }
 
     pub fn image_buff(&self) -> &[u8] {
-        &self.raw_image[1..]
+        &self.raw_image[1..self.image_size]
     }
     pub fn primary_data(&self) -> &[u8] {
         &self.raw_data[PRIMARY_DATA]
@@ -0,0 +1,16 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressFeedback {
+    Off,
+    Invert,
+    Frame,
+}
+
+impl From<u8> for PressFeedback {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PressFeedback::Invert,
+            2 => PressFeedback::Frame,
+            _ => PressFeedback::Off,
+        }
+    }
+}
@@ -1,14 +1,48 @@
+use freedeck::checksum::Crc32;
+
+use super::animation;
+use super::feedback::PressFeedback;
+use super::orientation::Orientation;
+use super::ConfigError;
 use super::IMAGE_SIZE;
 use super::ROW_SIZE;
 
 const HEADER_SIZE: u32 = ROW_SIZE;
 
+pub const DISPLAY_WIDTH: u8 = 128;
+pub const DEFAULT_DISPLAY_HEIGHT: u8 = 64;
+const DEFAULT_BRIGHTNESS: u8 = 0x5F;
+
+// header[20..24], crc32 of the whole file with these bytes read as zero, 0 if unchecked
+pub const CHECKSUM: core::ops::Range<usize> = 20..24;
+
+// header[32..64], zero terminated
+const PROFILE_NAME: core::ops::Range<usize> = 32..64;
+
+// header[7]
+const DIM_BEFORE_OFF: u8 = 0b0000_0001;
+const HOST_ACTIVITY_WAKES: u8 = 0b0000_0010;
+
 #[derive(Debug)]
 pub struct Header {
     pub width: u8,
     pub height: u8,
     pub bd_count: u32,
     pub page_count: u16,
+    pub orientation: Orientation,
+    pub screensaver_timeout: u16,
+    screensaver_flags: u8,
+    pub display_height: u8,
+    pub press_feedback: PressFeedback,
+    animations_offset: u32,
+    pub shift_interval: u8,
+    pub invert_interval: u8,
+    pub brightness: u8,
+    pub dim_brightness: u8,
+    dim_timeout: u16,
+    checksum: u32,
+    profile_key: u32,
+    profile_name: [u8; PROFILE_NAME.end - PROFILE_NAME.start],
     offset: u16,
 }
 
@@ -19,7 +53,33 @@ impl From<[u8; ROW_SIZE as usize]> for Header {
         let bd_count = width as u32 * height as u32;
 
         let offset = u16::from_le_bytes([header[2], header[3]]);
-        let page_count = offset / (width * height) as u16;
+        let page_count = offset.checked_div(bd_count as u16).unwrap_or(0);
+        let orientation = Orientation::from(header[4]);
+        let screensaver_timeout = u16::from_le_bytes([header[5], header[6]]);
+        let screensaver_flags = header[7];
+        let display_height = match header[8] {
+            0 => DEFAULT_DISPLAY_HEIGHT,
+            height => height,
+        };
+        let press_feedback = PressFeedback::from(header[9]);
+        // header[10..14], 0 if the file holds no animations
+        let animations_offset = u32::from_le_bytes(header[10..14].try_into().unwrap());
+        // minutes between pixel shifts and between inversions, 0 = off
+        let shift_interval = header[14];
+        let invert_interval = header[15];
+        // contrast while on, 0 keeps the default
+        let brightness = match header[16] {
+            0 => DEFAULT_BRIGHTNESS,
+            brightness => brightness,
+        };
+        let dim_brightness = header[17];
+        let dim_timeout = u16::from_le_bytes([header[18], header[19]]);
+        let checksum = u32::from_le_bytes(header[CHECKSUM].try_into().unwrap());
+        let profile_name = header[PROFILE_NAME].try_into().unwrap();
+        let mut crc = Crc32::new();
+        crc.update(&header[PROFILE_NAME]);
+        crc.update(&page_count.to_le_bytes());
+        let profile_key = crc.finish();
 
         Self {
             bd_count,
@@ -27,15 +87,87 @@ impl From<[u8; ROW_SIZE as usize]> for Header {
             height,
             offset,
             page_count,
+            orientation,
+            screensaver_timeout,
+            screensaver_flags,
+            display_height,
+            press_feedback,
+            animations_offset,
+            shift_interval,
+            invert_interval,
+            brightness,
+            dim_brightness,
+            dim_timeout,
+            checksum,
+            profile_key,
+            profile_name,
         }
     }
 }
 
 impl Header {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bd_count == 0 || !matches!(self.display_height, 32 | 64) {
+            return Err(ConfigError::BadHeader);
+        }
+        if self.page_count == 0 {
+            return Err(ConfigError::NoPages);
+        }
+        Ok(())
+    }
+    pub fn checksum(&self) -> Option<u32> {
+        match self.checksum {
+            0 => None,
+            checksum => Some(checksum),
+        }
+    }
+    // tells profiles apart by name and page count, editing a button keeps the key
+    pub fn profile_key(&self) -> u32 {
+        self.profile_key
+    }
+    pub fn profile_name(&self) -> &str {
+        let end = self
+            .profile_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.profile_name.len());
+        core::str::from_utf8(&self.profile_name[..end]).unwrap_or("")
+    }
     pub fn data_offset(&self, page: u16) -> u32 {
         ROW_SIZE * self.bd_count * page as u32 + HEADER_SIZE
     }
+    pub fn dims_before_off(&self) -> bool {
+        self.screensaver_flags & DIM_BEFORE_OFF != 0
+    }
+    // idle ms before dimming, without an own timeout halfway to screen off if enabled
+    pub fn dim_timeout(&self) -> u64 {
+        match self.dim_timeout {
+            0 if self.dims_before_off() => self.screensaver_timeout as u64 * 1000 / 2,
+            seconds => seconds as u64 * 1000,
+        }
+    }
+    pub fn host_activity_wakes(&self) -> bool {
+        self.screensaver_flags & HOST_ACTIVITY_WAKES != 0
+    }
+    // flag byte plus one bit per pixel
+    pub fn image_size(&self) -> u32 {
+        (1 + DISPLAY_WIDTH as u32 * self.display_height as u32 / 8).min(IMAGE_SIZE)
+    }
+    pub fn has_animations(&self) -> bool {
+        self.animations_offset != 0
+    }
+    // None if the offset in the header points past what a file can hold
+    pub fn animations_offset(&self, page: u16) -> Option<u32> {
+        animation::ENTRY_SIZE
+            .checked_mul(self.bd_count)
+            .and_then(|entries| entries.checked_mul(page as u32))
+            .and_then(|table| table.checked_add(self.animations_offset))
+    }
+    // the flag byte of an image is replaced by the frame duration
+    pub fn frame_size(&self) -> u32 {
+        animation::FRAME_HEADER_SIZE + self.image_size() - 1
+    }
     pub fn images_offset(&self, page: u16) -> u32 {
-        self.offset as u32 * ROW_SIZE + IMAGE_SIZE * self.bd_count * (page) as u32
+        self.offset as u32 * ROW_SIZE + self.image_size() * self.bd_count * (page) as u32
     }
 }
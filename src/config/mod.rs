@@ -1,68 +1,377 @@
 pub mod action;
+pub mod animation;
 pub mod button;
+pub mod feedback;
 pub mod header;
+pub mod orientation;
 pub mod page;
 
+use defmt::Format;
+use freedeck::checksum::Crc32;
+
 use crate::debug;
 use crate::BUTTON_COUNT;
 
 use header::Header;
 use page::Page;
 
-const ROW_SIZE: u32 = 128;
-const IMAGE_SIZE: u32 = 1025;
+pub const ROW_SIZE: u32 = 128;
+pub const IMAGE_SIZE: u32 = 1025;
+// the card is read a block at a time
+const BLOCK_SIZE: u32 = 512;
 
 type DataBuffs = [[u8; ROW_SIZE as usize]; BUTTON_COUNT];
 type ImagesBuffs = [[u8; IMAGE_SIZE as usize]; BUTTON_COUNT];
+type Animations = [[u8; animation::ENTRY_SIZE as usize]; BUTTON_COUNT];
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    NoCard,
+    NoFilesystem,
+    NoConfigFile,
+    BadHeader,
+    NoPages,
+    BadChecksum,
+}
+
+impl ConfigError {
+    // one line per display
+    pub fn message(&self) -> &'static [&'static str] {
+        match self {
+            ConfigError::NoCard => &["Error", "No SD card", "Insert card"],
+            ConfigError::NoFilesystem => &["Error", "SD card", "not FAT", "formatted"],
+            ConfigError::NoConfigFile => &["Error", "config.bin", "not found", "on SD card"],
+            ConfigError::BadHeader => &["Error", "config.bin", "bad header", "re-export it"],
+            ConfigError::NoPages => &["Error", "config.bin", "has no pages", "re-export it"],
+            ConfigError::BadChecksum => &["Error", "config.bin", "damaged", "copy it again"],
+        }
+    }
+}
 
 pub trait RWSeek {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()>;
     fn write(&mut self, buf: &[u8]) -> Result<usize, ()>;
     fn seek_from_start(&mut self, pos: u32) -> Result<(), ()>;
+    fn length(&self) -> u32;
+    // true while a block read ahead is still coming in, a read now would wait for it
+    fn is_busy(&mut self) -> bool {
+        false
+    }
+    // only the sd card keeps a log
+    fn append_log(&mut self, _text: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+    // the log files read as one, oldest first
+    fn read_log(&mut self, _position: u32, _buf: &mut [u8]) -> Result<usize, ()> {
+        Ok(0)
+    }
+    // reading the header again tells whether the file is still there
+    fn check(&mut self) -> Result<(), ()> {
+        let mut header_buf = [0u8; ROW_SIZE as usize];
+        self.seek_from_start(0)?;
+        match self.read(&mut header_buf)? {
+            read if read == header_buf.len() => Ok(()),
+            _ => Err(()),
+        }
+    }
+}
+
+// watches for a card while the flash copy is in use
+pub trait CardSlot {
+    // true once a card is in the slot that was not there before
+    fn inserted(&mut self) -> bool;
+}
+
+// the sd card is preferred, the flash copy is the fallback without one
+pub enum ConfigFile<S, F, P> {
+    Sd(S),
+    Flash(F, P),
+}
+
+impl<S: RWSeek, F: RWSeek, P: CardSlot> RWSeek for ConfigFile<S, F, P> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        match self {
+            ConfigFile::Sd(file) => file.read(buf),
+            ConfigFile::Flash(file, _) => file.read(buf),
+        }
+    }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+        match self {
+            ConfigFile::Sd(file) => file.write(buf),
+            ConfigFile::Flash(file, _) => file.write(buf),
+        }
+    }
+    fn seek_from_start(&mut self, pos: u32) -> Result<(), ()> {
+        match self {
+            ConfigFile::Sd(file) => file.seek_from_start(pos),
+            ConfigFile::Flash(file, _) => file.seek_from_start(pos),
+        }
+    }
+    fn length(&self) -> u32 {
+        match self {
+            ConfigFile::Sd(file) => file.length(),
+            ConfigFile::Flash(file, _) => file.length(),
+        }
+    }
+    fn is_busy(&mut self) -> bool {
+        match self {
+            ConfigFile::Sd(file) => file.is_busy(),
+            ConfigFile::Flash(file, _) => file.is_busy(),
+        }
+    }
+    fn append_log(&mut self, text: &[u8]) -> Result<(), ()> {
+        match self {
+            ConfigFile::Sd(file) => file.append_log(text),
+            ConfigFile::Flash(file, _) => file.append_log(text),
+        }
+    }
+    fn read_log(&mut self, position: u32, buf: &mut [u8]) -> Result<usize, ()> {
+        match self {
+            ConfigFile::Sd(file) => file.read_log(position, buf),
+            ConfigFile::Flash(file, _) => file.read_log(position, buf),
+        }
+    }
+    // a card showing up ends the fallback so it is mounted instead
+    fn check(&mut self) -> Result<(), ()> {
+        match self {
+            ConfigFile::Sd(file) => file.check(),
+            ConfigFile::Flash(file, slot) => match slot.inserted() {
+                true => Err(()),
+                false => file.check(),
+            },
+        }
+    }
+}
+
+fn read_header<C: RWSeek>(config_file: &mut C) -> Result<Header, ConfigError> {
+    let mut header_buf = [0u8; ROW_SIZE as usize];
+    match config_file.read(&mut header_buf) {
+        Ok(read) if read == header_buf.len() => {}
+        _ => return Err(ConfigError::BadHeader),
+    }
+    let header = Header::from(header_buf);
+    header.validate()?;
+    Ok(header)
+}
+
+// reads the whole file, header included, one without checksum passes unread
+fn verify<C: RWSeek>(config_file: &mut C, header: &Header) -> Result<(), ConfigError> {
+    let expected = match header.checksum() {
+        Some(checksum) => checksum,
+        None => return Ok(()),
+    };
+    config_file
+        .seek_from_start(0)
+        .map_err(|_| ConfigError::NoCard)?;
+    let mut crc = Crc32::new();
+    let mut buf = [0u8; 512];
+    let mut position = 0;
+    loop {
+        let read = match config_file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(()) => return Err(ConfigError::NoCard),
+        };
+        // the checksum can not cover itself
+        crc.update_zeroed(&buf[..read], position, &header::CHECKSUM);
+        position += read;
+    }
+    if crc.finish() != expected {
+        debug!("checksum {:x} instead of {:x}", crc.finish(), expected);
+        return Err(ConfigError::BadChecksum);
+    }
+    Ok(())
+}
+
+// checks a stored file without loading it, returns its checksum
+pub fn verify_file<C: RWSeek>(config_file: &mut C) -> Result<Option<u32>, ConfigError> {
+    config_file
+        .seek_from_start(0)
+        .map_err(|_| ConfigError::BadHeader)?;
+    let header = read_header(config_file)?;
+    verify(config_file, &header)?;
+    Ok(header.checksum())
 }
 
 pub struct Config<C> {
     pub header: Header,
     config_file: C,
     pub page: Page,
+    page_index: u16,
+    loading: Option<PageLoad>,
+}
+
+// a read never crosses a card block, so a page load holds up the main loop
+// for one block read at a time, and not at all while the next block comes in
+struct PageLoad {
+    page: u16,
+    // data rows, then images, then the animation table
+    part: usize,
+    // bytes of the part read so far
+    done: usize,
+    data_buffs: DataBuffs,
+    images_buffs: ImagesBuffs,
+    animations: Animations,
+}
+
+impl PageLoad {
+    fn new(page: u16) -> Self {
+        Self {
+            page,
+            part: 0,
+            done: 0,
+            data_buffs: [[0u8; ROW_SIZE as usize]; BUTTON_COUNT],
+            images_buffs: [[0u8; IMAGE_SIZE as usize]; BUTTON_COUNT],
+            animations: [[0u8; animation::ENTRY_SIZE as usize]; BUTTON_COUNT],
+        }
+    }
+
+    fn step<C: RWSeek>(
+        &mut self,
+        config_file: &mut C,
+        header: &Header,
+    ) -> Result<Option<Page>, ()> {
+        let image_size = header.image_size() as usize;
+        let (offset, entry_size) = match self.part {
+            0 => (header.data_offset(self.page), ROW_SIZE as usize),
+            1 => (header.images_offset(self.page), image_size),
+            2 if header.has_animations() => (
+                header.animations_offset(self.page).ok_or(())?,
+                animation::ENTRY_SIZE as usize,
+            ),
+            _ => {
+                return Ok(Some(Page::from((
+                    self.data_buffs,
+                    self.images_buffs,
+                    image_size,
+                    self.animations,
+                ))))
+            }
+        };
+        let button_index = self.done / entry_size;
+        let start = self.done % entry_size;
+        let position = offset.checked_add(self.done as u32).ok_or(())?;
+        let count = (entry_size - start).min((BLOCK_SIZE - position % BLOCK_SIZE) as usize);
+        let entry = match self.part {
+            0 => &mut self.data_buffs[button_index][..],
+            1 => &mut self.images_buffs[button_index][..image_size],
+            _ => &mut self.animations[button_index][..],
+        };
+        config_file.seek_from_start(position)?;
+        match config_file.read(&mut entry[start..start + count])? {
+            read if read == count => {}
+            _ => return Err(()),
+        }
+        self.done += count;
+        if self.done == entry_size * BUTTON_COUNT {
+            self.part += 1;
+            self.done = 0;
+        }
+        Ok(None)
+    }
 }
 
 impl<C> Config<C>
 where
     C: RWSeek,
 {
-    pub fn new(mut config_file: C) -> Self {
-        let mut header_buf = [0u8; ROW_SIZE as usize];
-        config_file.read(&mut header_buf).unwrap();
-
-        let header = Header::from(header_buf);
-        let page = Self::load_from_file(&mut config_file, &header, 0);
+    pub fn new(mut config_file: C) -> Result<Self, ConfigError> {
+        let header = read_header(&mut config_file)?;
+        verify(&mut config_file, &header)?;
+        let page =
+            Self::load_from_file(&mut config_file, &header, 0).map_err(|_| ConfigError::NoCard)?;
 
-        Self {
+        Ok(Self {
             config_file,
             header,
             page,
-        }
+            page_index: 0,
+            loading: None,
+        })
     }
 
-    fn load_from_file(config_file: &mut C, header: &Header, page: u16) -> Page {
-        let mut data_buffs: DataBuffs = [[0u8; ROW_SIZE as usize]; BUTTON_COUNT];
-        let data_offset = header.data_offset(page);
-        debug!("data_offset: {}", data_offset);
-        config_file.seek_from_start(data_offset).unwrap();
-        for button_index in 0..BUTTON_COUNT {
-            config_file.read(&mut data_buffs[button_index]).unwrap();
+    fn load_from_file(config_file: &mut C, header: &Header, page: u16) -> Result<Page, ()> {
+        let mut load = PageLoad::new(page);
+        loop {
+            if let Some(page) = load.step(config_file, header)? {
+                return Ok(page);
+            }
         }
-
-        let mut images_buffs: ImagesBuffs = [[0u8; IMAGE_SIZE as usize]; BUTTON_COUNT];
-        let images_offset = header.images_offset(page);
-        config_file.seek_from_start(images_offset).unwrap();
-        for button_index in 0..BUTTON_COUNT {
-            config_file.read(&mut images_buffs[button_index]).unwrap();
+    }
+    // the current page stays until the new one is read completely
+    pub fn start_loading(&mut self, page: u16) {
+        debug!("loading page {}", page);
+        self.loading = Some(PageLoad::new(page));
+    }
+    // reads a part of the page per call, true once the new page is in place
+    pub fn continue_loading(&mut self) -> Result<bool, ()> {
+        let load = match self.loading.as_mut() {
+            Some(load) => load,
+            None => return Ok(false),
+        };
+        if self.config_file.is_busy() {
+            return Ok(false);
         }
-        Page::from((data_buffs, images_buffs))
+        let page = match load.step(&mut self.config_file, &self.header) {
+            Ok(Some(page)) => page,
+            Ok(None) => return Ok(false),
+            Err(()) => {
+                self.loading = None;
+                return Err(());
+            }
+        };
+        self.page = page;
+        self.page_index = load.page;
+        self.loading = None;
+        Ok(true)
+    }
+    // reads a page in one go, for before anything is shown
+    pub fn load_page(&mut self, page: u16) -> Result<(), ()> {
+        self.start_loading(page);
+        while !self.continue_loading()? {}
+        Ok(())
     }
-    pub fn load_page(&mut self, page: u16) {
-        self.page = Self::load_from_file(&mut self.config_file, &self.header, page);
+    pub fn is_loading(&self) -> bool {
+        self.loading.is_some()
+    }
+    pub fn current_page(&self) -> u16 {
+        self.page_index
+    }
+    // fails once the config should be mounted again
+    pub fn check(&mut self) -> Result<(), ()> {
+        self.config_file.check()
+    }
+    pub fn append_log(&mut self, text: &[u8]) -> Result<(), ()> {
+        self.config_file.append_log(text)
+    }
+    pub fn read_log(&mut self, position: u32, buf: &mut [u8]) -> Result<usize, ()> {
+        self.config_file.read_log(position, buf)
+    }
+    pub fn file_length(&self) -> u32 {
+        self.config_file.length()
+    }
+    // the flash copy is read between page loads, so every read seeks first
+    pub fn read_at(&mut self, position: u32, buf: &mut [u8]) -> Result<usize, ()> {
+        self.config_file.seek_from_start(position)?;
+        self.config_file.read(buf)
+    }
+    // reads the pixels of one frame into image and returns how long to show it
+    pub fn read_frame(&mut self, index: usize, frame: u16, image: &mut [u8]) -> Result<u16, ()> {
+        let frame_size = self.header.frame_size();
+        // the table comes from the file, a bad one must not overflow
+        let offset = frame_size
+            .checked_mul(frame as u32)
+            .and_then(|frame_offset| frame_offset.checked_add(self.page.animations[index].offset))
+            .ok_or(())?;
+        self.config_file.seek_from_start(offset)?;
+
+        let mut duration = [0u8; animation::FRAME_HEADER_SIZE as usize];
+        self.config_file.read(&mut duration)?;
+        let pixels = &mut image[..(frame_size - animation::FRAME_HEADER_SIZE) as usize];
+        match self.config_file.read(pixels)? {
+            read if read == pixels.len() => {}
+            _ => return Err(()),
+        }
+        Ok(u16::from_le_bytes(duration).max(animation::MIN_FRAME_MS))
     }
 }
@@ -0,0 +1,41 @@
+const ROTATION: u8 = 0b0011;
+const MIRROR_HORIZONTAL: u8 = 0b0100;
+const MIRROR_VERTICAL: u8 = 0b1000;
+
+// bits 0-1: clockwise quarter turns, bit 2: mirror horizontal, bit 3: mirror vertical
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation(u8);
+
+impl From<u8> for Orientation {
+    fn from(value: u8) -> Self {
+        Self(value & (ROTATION | MIRROR_HORIZONTAL | MIRROR_VERTICAL))
+    }
+}
+
+impl Orientation {
+    // rotations add up, mirrors cancel each other out
+    pub fn then(self, other: Orientation) -> Self {
+        let rotation = (self.0 + other.0) & ROTATION;
+        let mirror = (self.0 ^ other.0) & (MIRROR_HORIZONTAL | MIRROR_VERTICAL);
+        Self(rotation | mirror)
+    }
+
+    // 90 and 270 degrees swap the image axes, which the panel cannot do itself
+    pub fn is_transposed(&self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    // (horizontal, vertical) panel flips applied after an optional transpose
+    pub fn flips(&self) -> (bool, bool) {
+        let (horizontal, vertical) = match self.0 & ROTATION {
+            0 => (false, false),
+            1 => (true, false),
+            2 => (true, true),
+            _ => (false, true),
+        };
+        (
+            horizontal ^ (self.0 & MIRROR_HORIZONTAL != 0),
+            vertical ^ (self.0 & MIRROR_VERTICAL != 0),
+        )
+    }
+}
@@ -1,20 +1,36 @@
 use crate::BUTTON_COUNT;
 
+use super::animation::Animation;
 use super::button::Button;
+use super::Animations;
 use super::DataBuffs;
 use super::ImagesBuffs;
 
 pub struct Page {
     pub buttons: [Button; BUTTON_COUNT],
+    pub animations: [Animation; BUTTON_COUNT],
 }
 
-impl From<(DataBuffs, ImagesBuffs)> for Page {
-    fn from((data_buffs, images_buffs): (DataBuffs, ImagesBuffs)) -> Self {
+impl From<(DataBuffs, ImagesBuffs, usize, Animations)> for Page {
+    fn from(
+        (data_buffs, images_buffs, image_size, animation_entries): (
+            DataBuffs,
+            ImagesBuffs,
+            usize,
+            Animations,
+        ),
+    ) -> Self {
         let buttons = core::array::from_fn::<_, BUTTON_COUNT, _>(|idx| Button {
             raw_data: data_buffs[idx],
             raw_image: images_buffs[idx],
+            image_size,
         });
 
-        Self { buttons }
+        let animations = animation_entries.map(Animation::from);
+
+        Self {
+            buttons,
+            animations,
+        }
     }
 }
@@ -0,0 +1,114 @@
+// fat timestamps hold 1980 up to the end of 2107
+pub const FAT_FIRST_YEAR: u16 = 1980;
+pub const FAT_LAST_YEAR: u16 = 2107;
+pub const FAT_START: u64 = 315_532_800;
+pub const FAT_END: u64 = 4_354_819_200;
+
+// unix times a file can be dated with
+pub fn in_fat_range(unix: u64) -> bool {
+    (FAT_START..FAT_END).contains(&unix)
+}
+
+#[derive(Clone, Copy)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+}
+
+impl From<u64> for DateTime {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    fn from(unix: u64) -> Self {
+        let (days, seconds) = (unix / 86_400, unix % 86_400);
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as u64;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hours: (seconds / 3600) as u8,
+            minutes: (seconds / 60 % 60) as u8,
+            seconds: (seconds % 60) as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+
+    fn date(unix: u64) -> (u16, u8, u8) {
+        let date_time = DateTime::from(unix);
+        (date_time.year, date_time.month, date_time.day)
+    }
+
+    fn time(unix: u64) -> (u8, u8, u8) {
+        let date_time = DateTime::from(unix);
+        (date_time.hours, date_time.minutes, date_time.seconds)
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(date(0), (1970, 1, 1));
+        assert_eq!(time(0), (0, 0, 0));
+        assert_eq!(date(DAY - 1), (1970, 1, 1));
+        assert_eq!(time(DAY - 1), (23, 59, 59));
+    }
+
+    #[test]
+    fn leap_day_2000() {
+        assert_eq!(date(951_782_400 - DAY), (2000, 2, 28));
+        assert_eq!(date(951_782_400), (2000, 2, 29));
+        assert_eq!(date(951_782_400 + DAY), (2000, 3, 1));
+    }
+
+    // 2100 is not a leap year
+    #[test]
+    fn no_leap_day_2100() {
+        assert_eq!(date(4_107_542_400 - DAY), (2100, 2, 28));
+        assert_eq!(date(4_107_542_400), (2100, 3, 1));
+    }
+
+    #[test]
+    fn time_of_day() {
+        let unix = 951_782_400 + 13 * 3600 + 37 * 60 + 5;
+        assert_eq!(date(unix), (2000, 2, 29));
+        assert_eq!(time(unix), (13, 37, 5));
+    }
+
+    #[test]
+    fn fat_bounds() {
+        assert_eq!(date(FAT_START), (FAT_FIRST_YEAR, 1, 1));
+        assert_eq!(time(FAT_START), (0, 0, 0));
+        assert_eq!(date(FAT_END - 1), (FAT_LAST_YEAR, 12, 31));
+        assert_eq!(time(FAT_END - 1), (23, 59, 59));
+        assert_eq!(date(FAT_END), (FAT_LAST_YEAR + 1, 1, 1));
+    }
+
+    #[test]
+    fn fat_range() {
+        assert!(!in_fat_range(0));
+        assert!(!in_fat_range(FAT_START - 1));
+        assert!(in_fat_range(FAT_START));
+        assert!(in_fat_range(FAT_END - 1));
+        assert!(!in_fat_range(FAT_END));
+        assert!(!in_fat_range(u64::MAX));
+    }
+}
@@ -0,0 +1,59 @@
+pub mod presence;
+pub mod remote;
+#[cfg(feature = "sh1106")]
+pub mod sh1106;
+#[cfg(not(feature = "sh1106"))]
+pub mod ssd1306_panel;
+
+use display_interface::DisplayError;
+
+use crate::config::button::Button;
+use crate::config::orientation::Orientation;
+use crate::render::Overlay;
+use crate::render::Shift;
+
+#[cfg(feature = "sh1106")]
+pub type Panel<DI> = sh1106::Sh1106<DI>;
+#[cfg(not(feature = "sh1106"))]
+pub type Panel<DI> = ssd1306_panel::Ssd1306Panel<DI>;
+
+pub trait Display {
+    // physical (width, height) in pixels
+    fn size(&self) -> (u8, u8);
+    fn init(&mut self) -> Result<(), DisplayError>;
+    fn draw(&mut self, buffer: &[u8]) -> Result<(), DisplayError>;
+    fn set_draw_area(&mut self, start: (u8, u8), end: (u8, u8)) -> Result<(), DisplayError>;
+    fn set_flips(&mut self, horizontal: bool, vertical: bool) -> Result<(), DisplayError>;
+    fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError>;
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError>;
+    fn set_invert(&mut self, invert: bool) -> Result<(), DisplayError>;
+    // returns once everything sent before reached the panel
+    fn flush(&mut self) {}
+}
+
+// the displays as core 0 sees them, the frames are rendered on the core that drives the panels
+pub trait Screens {
+    // physical (width, height) in pixels
+    fn size(&self) -> (u8, u8);
+    fn init(&mut self);
+    // swaps the panel driver, the displays have to be initialized again
+    fn resize(&mut self, height: u8);
+    fn set_contrast(&mut self, contrast: u8);
+    fn set_display_on(&mut self, on: bool);
+    fn set_invert(&mut self, invert: bool);
+    // orientation already includes the one of the button
+    fn draw_button(
+        &mut self,
+        button: &Button,
+        orientation: Orientation,
+        shift: Shift,
+        overlay: Overlay,
+    );
+    // an animation frame in place of the button image
+    fn draw_image(&mut self, image: &[u8], orientation: Orientation, shift: Shift);
+    fn draw_text(&mut self, text: &str);
+    // false while earlier draws still wait for the panels
+    fn is_idle(&self) -> bool;
+    // waits until the other core has worked through the queue
+    fn flush(&mut self);
+}
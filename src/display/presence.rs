@@ -0,0 +1,114 @@
+use core::cell::Cell;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use display_interface::DisplayError;
+
+use super::Display;
+use crate::debug;
+use crate::event;
+use crate::BUTTON_COUNT;
+
+const ATTEMPTS: u8 = 3;
+
+// one bit per display, written by the core that talks to the displays only
+static PRESENT: AtomicU8 = AtomicU8::new(0xFF);
+
+#[derive(Clone, Copy)]
+pub struct Present(u8);
+
+impl Present {
+    // flush the display first so this includes everything sent to it
+    pub fn now() -> Self {
+        Self(PRESENT.load(Ordering::Acquire))
+    }
+
+    pub fn is_present(&self, index: usize) -> bool {
+        self.0 & (1 << index) != 0
+    }
+
+    pub fn missing(self) -> impl Iterator<Item = usize> {
+        (0..BUTTON_COUNT).filter(move |&index| !self.is_present(index))
+    }
+
+    // the next present display to the right, wrapping around
+    pub fn neighbour(&self, index: usize) -> Option<usize> {
+        (1..BUTTON_COUNT)
+            .map(|distance| (index + distance) % BUTTON_COUNT)
+            .find(|&neighbour| self.is_present(neighbour))
+    }
+}
+
+fn set_present(index: usize, present: bool) {
+    let mask = PRESENT.load(Ordering::Relaxed);
+    let mask = match present {
+        true => mask | (1 << index),
+        false => mask & !(1 << index),
+    };
+    PRESENT.store(mask, Ordering::Release);
+}
+
+// skips displays that stopped answering so a single dead panel cannot hang the deck,
+// selected follows the mux address
+pub struct Presence<'a, D> {
+    display: D,
+    selected: &'a Cell<u8>,
+}
+
+impl<'a, D: Display> Presence<'a, D> {
+    pub fn new(display: D, selected: &'a Cell<u8>) -> Self {
+        Self { display, selected }
+    }
+
+    pub fn map<F: FnOnce(D) -> D>(self, f: F) -> Self {
+        Self::new(f(self.display), self.selected)
+    }
+
+    fn attempt<F>(&mut self, mut f: F) -> Result<(), DisplayError>
+    where
+        F: FnMut(&mut D) -> Result<(), DisplayError>,
+    {
+        let index = self.selected.get() as usize;
+        if !Present::now().is_present(index) {
+            return Ok(());
+        }
+        for _ in 0..ATTEMPTS {
+            if f(&mut self.display).is_ok() {
+                return Ok(());
+            }
+        }
+        debug!("display {} not responding", index);
+        event!("display {} not responding", index + 1);
+        set_present(index, false);
+        Ok(())
+    }
+}
+
+impl<'a, D: Display> Display for Presence<'a, D> {
+    fn size(&self) -> (u8, u8) {
+        self.display.size()
+    }
+    // probes the display again even if it was missing before
+    fn init(&mut self) -> Result<(), DisplayError> {
+        set_present(self.selected.get() as usize, true);
+        self.attempt(|display| display.init())
+    }
+    fn draw(&mut self, buffer: &[u8]) -> Result<(), DisplayError> {
+        self.attempt(|display| display.draw(buffer))
+    }
+    fn set_draw_area(&mut self, start: (u8, u8), end: (u8, u8)) -> Result<(), DisplayError> {
+        self.attempt(|display| display.set_draw_area(start, end))
+    }
+    fn set_flips(&mut self, horizontal: bool, vertical: bool) -> Result<(), DisplayError> {
+        self.attempt(|display| display.set_flips(horizontal, vertical))
+    }
+    fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        self.attempt(|display| display.set_contrast(contrast))
+    }
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        self.attempt(|display| display.set_display_on(on))
+    }
+    fn set_invert(&mut self, invert: bool) -> Result<(), DisplayError> {
+        self.attempt(|display| display.set_invert(invert))
+    }
+}
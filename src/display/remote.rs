@@ -0,0 +1,250 @@
+use core::cell::Cell;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering;
+
+use display_interface::WriteOnlyDataCommand;
+use heapless::spsc::Consumer;
+use heapless::spsc::Producer;
+use heapless::spsc::Queue;
+use rp_pico::hal::pac;
+use rp_pico::hal::sio::Sio;
+use rp_pico::hal::sio::SioFifo;
+
+use super::presence::Presence;
+use super::Display;
+use super::Panel;
+use super::Screens;
+use crate::config::button::Button;
+use crate::config::header::DEFAULT_DISPLAY_HEIGHT;
+use crate::config::header::DISPLAY_WIDTH;
+use crate::config::orientation::Orientation;
+use crate::config::IMAGE_SIZE;
+use crate::config::ROW_SIZE;
+use crate::mux::with_display;
+use crate::render::draw_button;
+use crate::render::draw_image;
+use crate::render::draw_text;
+use crate::render::Overlay;
+use crate::render::Shift;
+use crate::render::Shown;
+use crate::util::retry;
+use crate::BUTTON_COUNT;
+
+// each entry carries a whole image, a few are enough to keep core 1 busy
+const QUEUE_SIZE: usize = 8;
+
+// sent back over the sio fifo once core 1 got to a sync
+const SYNCED: u32 = 0x5359_4E43;
+
+enum Command {
+    Init,
+    // image size of the button, the image and data row travel in the entry
+    Button(usize, Orientation, Shift, Overlay),
+    Image(usize, Orientation, Shift),
+    Text(usize),
+    SetContrast(u8),
+    SetDisplayOn(bool),
+    SetInvert(bool),
+    Resize(u8),
+    Sync,
+}
+
+pub struct Entry {
+    index: u8,
+    command: Command,
+    image: [u8; IMAGE_SIZE as usize],
+    row: [u8; ROW_SIZE as usize],
+}
+
+type DisplayQueue = Queue<Entry, QUEUE_SIZE>;
+
+// core 1 runs from flash, it has to wait in ram while flash is written
+static PARK_REQUESTED: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicBool = AtomicBool::new(false);
+// counts the entries core 1 is done with, an entry is still drawn after it left the queue
+static DONE: AtomicU32 = AtomicU32::new(0);
+
+static mut QUEUE: DisplayQueue = Queue::new();
+
+// called once, core 0 keeps the producer and core 1 the consumer
+pub fn split() -> (
+    Producer<'static, Entry, QUEUE_SIZE>,
+    Consumer<'static, Entry, QUEUE_SIZE>,
+) {
+    unsafe { (*addr_of_mut!(QUEUE)).split() }
+}
+
+// stands in for the displays on core 0, every call is queued for core 1 and returns at once
+pub struct Remote<'a> {
+    queue: Producer<'static, Entry, QUEUE_SIZE>,
+    fifo: SioFifo,
+    selected: &'a Cell<u8>,
+    height: u8,
+    // entries queued so far
+    sent: u32,
+}
+
+impl<'a> Remote<'a> {
+    pub fn new(
+        queue: Producer<'static, Entry, QUEUE_SIZE>,
+        fifo: SioFifo,
+        selected: &'a Cell<u8>,
+    ) -> Self {
+        Self {
+            queue,
+            fifo,
+            selected,
+            height: DEFAULT_DISPLAY_HEIGHT,
+            sent: 0,
+        }
+    }
+
+    // waits only while the queue is full
+    fn send(&mut self, command: Command, image: &[u8], row: &[u8]) {
+        let mut entry = Entry {
+            index: self.selected.get(),
+            command,
+            image: [0; IMAGE_SIZE as usize],
+            row: [0; ROW_SIZE as usize],
+        };
+        entry.image[..image.len()].copy_from_slice(image);
+        entry.row[..row.len()].copy_from_slice(row);
+        while let Err(rejected) = self.queue.enqueue(entry) {
+            entry = rejected;
+        }
+        self.sent = self.sent.wrapping_add(1);
+    }
+}
+
+impl Screens for Remote<'_> {
+    fn size(&self) -> (u8, u8) {
+        (DISPLAY_WIDTH, self.height)
+    }
+    fn init(&mut self) {
+        self.send(Command::Init, &[], &[]);
+    }
+    fn resize(&mut self, height: u8) {
+        self.height = height;
+        self.send(Command::Resize(height), &[], &[]);
+    }
+    fn set_contrast(&mut self, contrast: u8) {
+        self.send(Command::SetContrast(contrast), &[], &[]);
+    }
+    fn set_display_on(&mut self, on: bool) {
+        self.send(Command::SetDisplayOn(on), &[], &[]);
+    }
+    fn set_invert(&mut self, invert: bool) {
+        self.send(Command::SetInvert(invert), &[], &[]);
+    }
+    fn draw_button(
+        &mut self,
+        button: &Button,
+        orientation: Orientation,
+        shift: Shift,
+        overlay: Overlay,
+    ) {
+        let command = Command::Button(button.image_size, orientation, shift, overlay);
+        self.send(command, &button.raw_image, &button.raw_data);
+    }
+    fn draw_image(&mut self, image: &[u8], orientation: Orientation, shift: Shift) {
+        self.send(Command::Image(image.len(), orientation, shift), image, &[]);
+    }
+    fn draw_text(&mut self, text: &str) {
+        self.send(Command::Text(text.len()), text.as_bytes(), &[]);
+    }
+    fn is_idle(&self) -> bool {
+        DONE.load(Ordering::Acquire) == self.sent
+    }
+    fn flush(&mut self) {
+        self.send(Command::Sync, &[], &[]);
+        while self.fifo.read_blocking() != SYNCED {}
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+fn park() {
+    PARKED.store(true, Ordering::Release);
+    while PARK_REQUESTED.load(Ordering::Acquire) {}
+    PARKED.store(false, Ordering::Release);
+}
+
+// called from core 0, core 1 finishes the command it is working on first
+pub fn with_core1_parked<T>(f: impl FnOnce() -> T) -> T {
+    PARK_REQUESTED.store(true, Ordering::Release);
+    while !PARKED.load(Ordering::Acquire) {}
+    let result = f();
+    PARK_REQUESTED.store(false, Ordering::Release);
+    while PARKED.load(Ordering::Acquire) {}
+    result
+}
+
+// renders here so core 0 only copies the button over
+fn run<D: Display>(display: &mut D, entry: Entry, shown: &mut Shown) {
+    match entry.command {
+        Command::Init => {
+            retry(|| display.init());
+            *shown = None;
+        }
+        Command::Button(image_size, orientation, shift, overlay) => {
+            let button = Button {
+                raw_image: entry.image,
+                raw_data: entry.row,
+                image_size,
+            };
+            draw_button(display, &button, orientation, shift, overlay, shown);
+        }
+        Command::Image(len, orientation, shift) => {
+            draw_image(display, &entry.image[..len], orientation, shift, shown)
+        }
+        Command::Text(len) => {
+            let text = core::str::from_utf8(&entry.image[..len]).unwrap_or("");
+            draw_text(display, text);
+            *shown = None;
+        }
+        Command::SetContrast(contrast) => retry(|| display.set_contrast(contrast)),
+        Command::SetDisplayOn(on) => retry(|| display.set_display_on(on)),
+        Command::SetInvert(invert) => retry(|| display.set_invert(invert)),
+        Command::Resize(_) | Command::Sync => {}
+    }
+}
+
+// runs on core 1, the only core that renders and talks to the displays
+pub fn serve<DI>(interface: DI, mut queue: Consumer<'static, Entry, QUEUE_SIZE>) -> !
+where
+    DI: WriteOnlyDataCommand,
+{
+    let pac = unsafe { pac::Peripherals::steal() };
+    let mut sio = Sio::new(pac.SIO);
+    let selected = Cell::new(0);
+    let mut display = Presence::new(Panel::new(interface, DEFAULT_DISPLAY_HEIGHT), &selected);
+    let mut shown: [Shown; BUTTON_COUNT] = [None; BUTTON_COUNT];
+    loop {
+        if PARK_REQUESTED.load(Ordering::Acquire) {
+            park();
+        }
+        let entry = match queue.dequeue() {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let index = entry.index;
+        selected.set(index);
+        match entry.command {
+            Command::Resize(height) => {
+                display = display.map(|panel| Panel::new(panel.release(), height));
+                shown = [None; BUTTON_COUNT];
+            }
+            Command::Sync => sio.fifo.write_blocking(SYNCED),
+            _ => with_display(index, || {
+                run(&mut display, entry, &mut shown[index as usize])
+            }),
+        }
+        // only core 1 writes it, thumbv6 has no atomic add
+        DONE.store(
+            DONE.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Release,
+        );
+    }
+}
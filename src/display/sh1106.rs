@@ -0,0 +1,123 @@
+use display_interface::DataFormat::U8;
+use display_interface::DisplayError;
+use display_interface::WriteOnlyDataCommand;
+
+use super::Display;
+use crate::config::header::DISPLAY_WIDTH;
+
+// the controller has 132 columns of RAM, panels show the middle 128
+const COLUMN_OFFSET: u8 = 2;
+
+const DISPLAY_OFF: u8 = 0xAE;
+const DISPLAY_ON: u8 = 0xAF;
+const CONTRAST: u8 = 0x81;
+const INVERT_ON: u8 = 0xA7;
+const INVERT_OFF: u8 = 0xA6;
+const SEGMENT_REMAP: u8 = 0xA1;
+const SEGMENT_NORMAL: u8 = 0xA0;
+const COM_REVERSE: u8 = 0xC8;
+const COM_NORMAL: u8 = 0xC0;
+const PAGE_ADDRESS: u8 = 0xB0;
+const COLUMN_LOW: u8 = 0x00;
+const COLUMN_HIGH: u8 = 0x10;
+
+pub struct Sh1106<DI> {
+    interface: DI,
+    width: u8,
+    height: u8,
+    area: ((u8, u8), (u8, u8)),
+}
+
+impl<DI> Sh1106<DI>
+where
+    DI: WriteOnlyDataCommand,
+{
+    pub fn new(interface: DI, height: u8) -> Self {
+        Self {
+            interface,
+            width: DISPLAY_WIDTH,
+            height,
+            area: ((0, 0), (DISPLAY_WIDTH, height)),
+        }
+    }
+
+    pub fn release(self) -> DI {
+        self.interface
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<(), DisplayError> {
+        self.interface.send_commands(U8(command))
+    }
+}
+
+impl<DI> Display for Sh1106<DI>
+where
+    DI: WriteOnlyDataCommand,
+{
+    fn size(&self) -> (u8, u8) {
+        (self.width, self.height)
+    }
+    fn init(&mut self) -> Result<(), DisplayError> {
+        let com_pins = if self.height == 64 { 0x12 } else { 0x02 };
+        self.command(&[
+            DISPLAY_OFF,
+            0xD5, // clock divide
+            0x80,
+            0xA8, // multiplex ratio
+            self.height - 1,
+            0xD3, // display offset
+            0x00,
+            0x40, // start line 0
+            0xAD, // dc-dc converter on
+            0x8B,
+            0xDA, // com pins
+            com_pins,
+            0xD9, // precharge
+            0x22,
+            0xDB, // vcom deselect level
+            0x35,
+            0xA4, // show ram content
+            INVERT_OFF,
+        ])?;
+        self.set_flips(false, false)?;
+        self.area = ((0, 0), (self.width, self.height));
+        self.command(&[DISPLAY_ON])
+    }
+    // there is no horizontal addressing mode, so every page is addressed on its own
+    fn draw(&mut self, buffer: &[u8]) -> Result<(), DisplayError> {
+        let ((start_x, start_y), (end_x, end_y)) = self.area;
+        let column = start_x + COLUMN_OFFSET;
+        let pages = start_y / 8..(end_y + 7) / 8;
+        for (page, row) in pages.zip(buffer.chunks((end_x - start_x) as usize)) {
+            self.command(&[
+                PAGE_ADDRESS | page,
+                COLUMN_LOW | (column & 0x0F),
+                COLUMN_HIGH | (column >> 4),
+            ])?;
+            self.interface.send_data(U8(row))?;
+        }
+        Ok(())
+    }
+    fn set_draw_area(&mut self, start: (u8, u8), end: (u8, u8)) -> Result<(), DisplayError> {
+        self.area = (start, end);
+        Ok(())
+    }
+    fn set_flips(&mut self, horizontal: bool, vertical: bool) -> Result<(), DisplayError> {
+        let segment = if horizontal {
+            SEGMENT_NORMAL
+        } else {
+            SEGMENT_REMAP
+        };
+        let com = if vertical { COM_NORMAL } else { COM_REVERSE };
+        self.command(&[segment, com])
+    }
+    fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        self.command(&[CONTRAST, contrast])
+    }
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        self.command(&[if on { DISPLAY_ON } else { DISPLAY_OFF }])
+    }
+    fn set_invert(&mut self, invert: bool) -> Result<(), DisplayError> {
+        self.command(&[if invert { INVERT_ON } else { INVERT_OFF }])
+    }
+}
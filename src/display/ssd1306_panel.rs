@@ -0,0 +1,121 @@
+use display_interface::DisplayError;
+use ssd1306::mode::BufferedGraphicsMode;
+use ssd1306::mode::DisplayConfig;
+use ssd1306::prelude::Brightness;
+use ssd1306::prelude::DisplayRotation;
+use ssd1306::prelude::WriteOnlyDataCommand;
+use ssd1306::size::DisplaySize;
+use ssd1306::size::DisplaySize128x32;
+use ssd1306::size::DisplaySize128x64;
+use ssd1306::Ssd1306;
+
+use super::Display;
+
+impl<DI, SIZE> Display for Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    fn size(&self) -> (u8, u8) {
+        (SIZE::WIDTH, SIZE::HEIGHT)
+    }
+    fn init(&mut self) -> Result<(), DisplayError> {
+        DisplayConfig::init(self)
+    }
+    fn draw(&mut self, buffer: &[u8]) -> Result<(), DisplayError> {
+        Ssd1306::draw(self, buffer)
+    }
+    fn set_draw_area(&mut self, start: (u8, u8), end: (u8, u8)) -> Result<(), DisplayError> {
+        Ssd1306::set_draw_area(self, start, end)
+    }
+    fn set_flips(&mut self, horizontal: bool, vertical: bool) -> Result<(), DisplayError> {
+        let (rotation, mirror) = match (horizontal, vertical) {
+            (false, false) => (DisplayRotation::Rotate0, false),
+            (true, false) => (DisplayRotation::Rotate0, true),
+            (true, true) => (DisplayRotation::Rotate180, false),
+            (false, true) => (DisplayRotation::Rotate180, true),
+        };
+        Ssd1306::set_rotation(self, rotation)?;
+        Ssd1306::set_mirror(self, mirror)
+    }
+    fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        // same precharge periods as the Brightness presets
+        let precharge = if contrast == 0 { 0x1 } else { 0x2 };
+        Ssd1306::set_brightness(self, Brightness::custom(precharge, contrast))
+    }
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        Ssd1306::set_display_on(self, on)
+    }
+    fn set_invert(&mut self, invert: bool) -> Result<(), DisplayError> {
+        Ssd1306::set_invert(self, invert)
+    }
+}
+
+type Buffered<DI, SIZE> = Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>;
+
+macro_rules! with_panel {
+    ($panel:expr, $display:ident => $call:expr) => {
+        match $panel {
+            Ssd1306Panel::Size128x64($display) => $call,
+            Ssd1306Panel::Size128x32($display) => $call,
+        }
+    };
+}
+
+// the panel size is a type parameter of Ssd1306 but only known once the config is read
+pub enum Ssd1306Panel<DI> {
+    Size128x64(Buffered<DI, DisplaySize128x64>),
+    Size128x32(Buffered<DI, DisplaySize128x32>),
+}
+
+impl<DI> Ssd1306Panel<DI>
+where
+    DI: WriteOnlyDataCommand,
+{
+    pub fn new(interface: DI, height: u8) -> Self {
+        match height {
+            32 => Self::Size128x32(
+                Ssd1306::new(interface, DisplaySize128x32, DisplayRotation::Rotate0)
+                    .into_buffered_graphics_mode(),
+            ),
+            _ => Self::Size128x64(
+                Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
+                    .into_buffered_graphics_mode(),
+            ),
+        }
+    }
+
+    pub fn release(self) -> DI {
+        with_panel!(self, display => display.release())
+    }
+}
+
+impl<DI> Display for Ssd1306Panel<DI>
+where
+    DI: WriteOnlyDataCommand,
+{
+    fn size(&self) -> (u8, u8) {
+        with_panel!(self, display => Display::size(display))
+    }
+    fn init(&mut self) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::init(display))
+    }
+    fn draw(&mut self, buffer: &[u8]) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::draw(display, buffer))
+    }
+    fn set_draw_area(&mut self, start: (u8, u8), end: (u8, u8)) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::set_draw_area(display, start, end))
+    }
+    fn set_flips(&mut self, horizontal: bool, vertical: bool) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::set_flips(display, horizontal, vertical))
+    }
+    fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::set_contrast(display, contrast))
+    }
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::set_display_on(display, on))
+    }
+    fn set_invert(&mut self, invert: bool) -> Result<(), DisplayError> {
+        with_panel!(self, display => Display::set_invert(display, invert))
+    }
+}
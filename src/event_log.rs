@@ -0,0 +1,197 @@
+use core::cell::RefCell;
+use core::fmt::Arguments;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use cortex_m::interrupt::Mutex;
+use heapless::Deque;
+use heapless::String;
+use rp_pico::hal::pac;
+use rp_pico::hal::sio::Spinlock2;
+
+use crate::clock::DateTime;
+use crate::clock::Micros;
+use crate::clock::WallClock;
+
+const LINE_SIZE: usize = 64;
+const BUFFERED: usize = 32;
+pub const FLUSH_SIZE: usize = 1024;
+
+// sent over serial to get the log files back
+const DUMP_COMMAND: [u8; 4] = *b"FDLG";
+
+const PANIC_MAGIC: u32 = 0x5041_4E43;
+
+type Line = String<LINE_SIZE>;
+
+struct Record {
+    at: Micros,
+    text: Line,
+}
+
+// events wait here until the sd card takes them
+struct Buffer {
+    records: Deque<Record, BUFFERED>,
+    dropped: u32,
+}
+
+static BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer {
+    records: Deque::new(),
+    dropped: 0,
+}));
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct PanicNote {
+    magic: u32,
+    len: usize,
+    text: [u8; LINE_SIZE],
+}
+
+// survives the reset after a panic, so it can be logged on the next boot
+#[link_section = ".uninit.PANIC_NOTE"]
+static mut PANIC_NOTE: MaybeUninit<PanicNote> = MaybeUninit::uninit();
+
+#[macro_export]
+macro_rules! event {
+    ($($all:tt)*) => {
+        $crate::event_log::log(format_args!($($all)*))
+    };
+}
+
+// both cores log, masking interrupts alone does not keep them apart
+fn with_buffer<T>(f: impl FnOnce(&mut Buffer) -> T) -> T {
+    cortex_m::interrupt::free(|cs| {
+        let _lock = Spinlock2::claim();
+        f(&mut BUFFER.borrow(cs).borrow_mut())
+    })
+}
+
+// the timer runs on its own, reading it needs no handle
+fn uptime() -> Micros {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let high = timer.timerawh.read().bits();
+        let low = timer.timerawl.read().bits();
+        if timer.timerawh.read().bits() == high {
+            return Micros::from_ticks((high as u64) << 32 | low as u64);
+        }
+    }
+}
+
+// the oldest event is dropped once the buffer is full
+pub fn log(args: Arguments) {
+    let mut text = Line::new();
+    let _ = text.write_fmt(args);
+    let record = Record { at: uptime(), text };
+    with_buffer(|buffer| {
+        if buffer.records.is_full() {
+            buffer.records.pop_front();
+            buffer.dropped += 1;
+        }
+        let _ = buffer.records.push_back(record);
+    });
+}
+
+// dated once the host set the clock, seconds since boot before
+fn write_record(text: &mut String<FLUSH_SIZE>, record: &Record, clock: &WallClock) -> bool {
+    let mut line: String<{ LINE_SIZE + 32 }> = String::new();
+    let _ = match clock.at(record.at) {
+        Some(unix) => {
+            let at = DateTime::from(unix);
+            write!(
+                line,
+                "{}-{:02}-{:02} {:02}:{:02}:{:02} ",
+                at.year, at.month, at.day, at.hours, at.minutes, at.seconds
+            )
+        }
+        None => write!(
+            line,
+            "+{}.{:03} ",
+            record.at.ticks() / 1_000_000,
+            record.at.ticks() / 1000 % 1000
+        ),
+    };
+    let _ = write!(line, "{}\r\n", record.text);
+    text.push_str(&line).is_ok()
+}
+
+// the events and the drop count in text, they stay buffered until written() is called
+pub fn pending(clock: &WallClock, text: &mut String<FLUSH_SIZE>) -> (usize, u32) {
+    with_buffer(|buffer| {
+        let mut dropped = 0;
+        if buffer.dropped > 0 {
+            let mut line = Line::new();
+            let _ = write!(line, "{} events dropped", buffer.dropped);
+            let record = Record {
+                at: uptime(),
+                text: line,
+            };
+            if write_record(text, &record, clock) {
+                dropped = buffer.dropped;
+            }
+        }
+        let count = buffer
+            .records
+            .iter()
+            .take_while(|record| write_record(text, record, clock))
+            .count();
+        (count, dropped)
+    })
+}
+
+// events dropped since pending() are still counted
+pub fn written(count: usize, dropped: u32) {
+    with_buffer(|buffer| {
+        buffer.dropped -= dropped;
+        for _ in 0..count {
+            buffer.records.pop_front();
+        }
+    });
+}
+
+// true if data was a dump command
+pub fn receive(data: &[u8]) -> bool {
+    if data.len() < 4 || data[..4] != DUMP_COMMAND {
+        return false;
+    }
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+    true
+}
+
+pub fn dump_requested() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+pub fn log_last_panic() {
+    let note = unsafe { &mut *addr_of_mut!(PANIC_NOTE).cast::<PanicNote>() };
+    if note.magic != PANIC_MAGIC {
+        return;
+    }
+    note.magic = 0;
+    let text = &note.text[..note.len.min(LINE_SIZE)];
+    log(format_args!(
+        "{}",
+        core::str::from_utf8(text).unwrap_or("panic")
+    ));
+}
+
+// notes the panic and restarts, a deck out in the field has no probe attached
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    defmt::error!("{}", defmt::Display2Format(info));
+    let mut text = Line::new();
+    let _ = write!(text, "panic: {}", info);
+    let mut note = PanicNote {
+        magic: PANIC_MAGIC,
+        len: text.len(),
+        text: [0; LINE_SIZE],
+    };
+    note.text[..text.len()].copy_from_slice(text.as_bytes());
+    unsafe { addr_of_mut!(PANIC_NOTE).cast::<PanicNote>().write(note) };
+    cortex_m::peripheral::SCB::sys_reset();
+}
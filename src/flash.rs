@@ -0,0 +1,376 @@
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+use crate::config::verify_file;
+use crate::config::Config;
+use crate::config::ConfigError;
+use crate::config::RWSeek;
+use crate::debug;
+use crate::display::remote::with_core1_parked;
+use crate::event;
+
+const XIP_BASE: u32 = 0x1000_0000;
+// keep in sync with memory.x
+const CONFIG_OFFSET: u32 = 0x10_0000;
+const CONFIG_SIZE: u32 = 0x10_0000;
+pub const SECTOR_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 256;
+
+// the last sectors hold the runtime state, see src/state.rs
+pub const STATE_SIZE: u32 = 2 * SECTOR_SIZE as u32;
+pub const STATE_OFFSET: u32 = CONFIG_SIZE - STATE_SIZE;
+
+// a new copy goes into the other slot, so the last good one survives until it is complete
+const SLOT_COUNT: u32 = 2;
+const SLOT_SIZE: u32 = STATE_OFFSET / SLOT_COUNT;
+
+// the first sector of a slot holds magic, length, sequence and checksum, the config file follows
+const MAGIC: [u8; 4] = *b"FDCF";
+const DATA_OFFSET: u32 = SECTOR_SIZE as u32;
+const CAPACITY: u32 = SLOT_SIZE - DATA_OFFSET;
+
+// sent over serial before the file, followed by its length u32 LE
+const UPLOAD_COMMAND: [u8; 4] = *b"FDUP";
+// sent over serial on its own to drop an upload in progress
+const ABORT_COMMAND: [u8; 4] = *b"FDAB";
+// a host that stops sending for this long has given up on the upload
+const UPLOAD_TIMEOUT_MS: u64 = 2000;
+// a sector written for the copy from the card stalls both cores and usb for typically
+// 45 ms and up to about 400 ms, the main loop gets the time in between
+const COPY_SECTOR_MS: u64 = 500;
+
+pub fn flash_bytes(offset: u32, len: usize) -> &'static [u8] {
+    let address = XIP_BASE + CONFIG_OFFSET + offset;
+    unsafe { core::slice::from_raw_parts(address as *const u8, len) }
+}
+
+// nothing may run from flash while it is written, on either core
+pub fn write_sector(offset: u32, sector: &[u8; SECTOR_SIZE]) {
+    with_core1_parked(|| {
+        cortex_m::interrupt::free(|_| unsafe {
+            rp2040_flash::flash::flash_range_erase_and_program(
+                CONFIG_OFFSET + offset,
+                sector,
+                true,
+            );
+        })
+    });
+}
+
+// without erasing first, so only bits that are still set can be cleared
+pub fn program_page(offset: u32, page: &[u8; PAGE_SIZE]) {
+    with_core1_parked(|| {
+        cortex_m::interrupt::free(|_| unsafe {
+            rp2040_flash::flash::flash_range_program(CONFIG_OFFSET + offset, page, true);
+        })
+    });
+}
+
+struct Slot {
+    index: u32,
+    length: u32,
+    sequence: u32,
+    checksum: u32,
+}
+
+impl Slot {
+    fn read(index: u32) -> Option<Self> {
+        let header = flash_bytes(index * SLOT_SIZE, 16);
+        if header[..4] != MAGIC {
+            return None;
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let length = field(4);
+        if length > CAPACITY {
+            return None;
+        }
+        Some(Self {
+            index,
+            length,
+            sequence: field(8),
+            checksum: field(12),
+        })
+    }
+}
+
+fn newest_slot() -> Option<Slot> {
+    (0..SLOT_COUNT)
+        .filter_map(Slot::read)
+        .max_by(|a, b| (a.sequence.wrapping_sub(b.sequence) as i32).cmp(&0))
+}
+
+pub struct FlashConfigFile {
+    base: u32,
+    length: u32,
+    position: u32,
+}
+
+impl FlashConfigFile {
+    pub fn new() -> Result<Self, ConfigError> {
+        let slot = newest_slot().ok_or(ConfigError::NoConfigFile)?;
+        debug!("config in flash slot {}, {} bytes", slot.index, slot.length);
+        Ok(Self::in_slot(slot.index, slot.length))
+    }
+
+    fn in_slot(index: u32, length: u32) -> Self {
+        Self {
+            base: index * SLOT_SIZE + DATA_OFFSET,
+            length,
+            position: 0,
+        }
+    }
+}
+
+impl RWSeek for FlashConfigFile {
+    fn seek_from_start(&mut self, pos: u32) -> Result<(), ()> {
+        if pos > self.length {
+            return Err(());
+        }
+        self.position = pos;
+        Ok(())
+    }
+    fn length(&self) -> u32 {
+        self.length
+    }
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let read = buf.len().min((self.length - self.position) as usize);
+        buf[..read].copy_from_slice(flash_bytes(self.base + self.position, read));
+        self.position += read as u32;
+        Ok(read)
+    }
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+}
+
+pub enum UploadEvent {
+    Started,
+    Done,
+    TooLarge,
+    Damaged,
+    Aborted,
+    TimedOut,
+}
+
+// writes a config file into the older slot, over serial or from the sd card
+pub struct FlashUpload<'a> {
+    timer: &'a dyn Monotonic,
+    last_data: Micros,
+    slot: u32,
+    sequence: u32,
+    length: u32,
+    received: u32,
+    active: bool,
+    too_large: bool,
+    sector: [u8; SECTOR_SIZE],
+    filled: usize,
+    // the checksum of the config.bin being copied from the card
+    copying: Option<u32>,
+    copied_at: Micros,
+    // the checksum of a config flash could not keep, it is not copied again
+    rejected: Option<u32>,
+}
+
+impl<'a> FlashUpload<'a> {
+    pub fn new(timer: &'a dyn Monotonic) -> Self {
+        Self {
+            timer,
+            last_data: timer.now(),
+            slot: 0,
+            sequence: 0,
+            length: 0,
+            received: 0,
+            active: false,
+            too_large: false,
+            sector: [0xFF; SECTOR_SIZE],
+            filled: 0,
+            copying: None,
+            copied_at: timer.now(),
+            rejected: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn begin(&mut self) {
+        (self.slot, self.sequence) = match newest_slot() {
+            Some(newest) => (
+                (newest.index + 1) % SLOT_COUNT,
+                newest.sequence.wrapping_add(1),
+            ),
+            None => (0, 0),
+        };
+        write_sector(self.slot * SLOT_SIZE, &[0xFF; SECTOR_SIZE]);
+        self.received = 0;
+        self.filled = 0;
+        self.too_large = false;
+        self.sector = [0xFF; SECTOR_SIZE];
+        self.last_data = self.timer.now();
+    }
+
+    fn flush_sector(&mut self) {
+        let offset = self.slot * SLOT_SIZE + DATA_OFFSET + self.received - self.filled as u32;
+        write_sector(offset, &self.sector);
+        self.sector = [0xFF; SECTOR_SIZE];
+        self.filled = 0;
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let room = (CAPACITY - self.received) as usize;
+        self.too_large |= data.len() > room;
+        for &byte in data.iter().take(room) {
+            self.sector[self.filled] = byte;
+            self.filled += 1;
+            self.received += 1;
+            if self.filled == SECTOR_SIZE {
+                self.flush_sector();
+            }
+        }
+    }
+
+    // the slot header is written last and only for a file that checks out,
+    // so an interrupted or damaged copy never replaces the last good one
+    fn commit(&mut self) -> bool {
+        self.active = false;
+        if self.filled > 0 {
+            self.flush_sector();
+        }
+        if self.too_large {
+            return false;
+        }
+        let mut file = FlashConfigFile::in_slot(self.slot, self.received);
+        let checksum = match verify_file(&mut file) {
+            Ok(checksum) => checksum.unwrap_or(0),
+            Err(error) => {
+                debug!("config for flash rejected: {}", error);
+                return false;
+            }
+        };
+        let mut header = [0xFF; SECTOR_SIZE];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&self.received.to_le_bytes());
+        header[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+        write_sector(self.slot * SLOT_SIZE, &header);
+        debug!("stored {} bytes in flash slot {}", self.received, self.slot);
+        true
+    }
+
+    fn start(&mut self, command: &[u8]) -> Option<UploadEvent> {
+        if command.len() < 8 || command[..4] != UPLOAD_COMMAND {
+            return None;
+        }
+        let length = u32::from_le_bytes(command[4..8].try_into().unwrap());
+        if length > CAPACITY {
+            return Some(UploadEvent::TooLarge);
+        }
+        self.length = length;
+        // the uploaded file replaces a copy from the card in the same slot
+        self.copying = None;
+        self.begin();
+        self.active = true;
+        match self.receive(&command[8..]) {
+            None => Some(UploadEvent::Started),
+            event => event,
+        }
+    }
+
+    pub fn receive(&mut self, data: &[u8]) -> Option<UploadEvent> {
+        if !self.active {
+            return self.start(data);
+        }
+        if data[..] == ABORT_COMMAND {
+            debug!("upload aborted by the host");
+            self.active = false;
+            return Some(UploadEvent::Aborted);
+        }
+        self.last_data = self.timer.now();
+        let wanted = (self.length - self.received) as usize;
+        self.push(&data[..wanted.min(data.len())]);
+        if self.received < self.length {
+            return None;
+        }
+        match self.commit() {
+            true => Some(UploadEvent::Done),
+            false => Some(UploadEvent::Damaged),
+        }
+    }
+
+    // without it a vanished host would leave every later command swallowed as file data
+    pub fn expire(&mut self) -> Option<UploadEvent> {
+        if !self.active {
+            return None;
+        }
+        let idle = self.timer.now().checked_duration_since(self.last_data)?;
+        if idle.to_millis() < UPLOAD_TIMEOUT_MS {
+            return None;
+        }
+        debug!("upload timed out after {} bytes", self.received);
+        self.active = false;
+        Some(UploadEvent::TimedOut)
+    }
+
+    // starts keeping a checksummed config as the last good copy, unless flash holds it already
+    pub fn retain<C: RWSeek>(&mut self, config: &mut Config<C>) {
+        let checksum = match config.header.checksum() {
+            Some(checksum) => checksum,
+            None => return,
+        };
+        if self.active || self.rejected == Some(checksum) {
+            return;
+        }
+        if newest_slot().map(|slot| slot.checksum) == Some(checksum) {
+            return;
+        }
+        let length = config.file_length();
+        if length > CAPACITY {
+            event!("config.bin has {} bytes, too large for flash", length);
+            self.rejected = Some(checksum);
+            return;
+        }
+        self.length = length;
+        self.begin();
+        self.copying = Some(checksum);
+        self.copied_at = self.timer.now();
+    }
+
+    // copies a sector at most every COPY_SECTOR_MS, erasing the whole slot at once
+    // would stall usb for seconds
+    pub fn continue_copy<C: RWSeek>(&mut self, config: &mut Config<C>) {
+        let checksum = match self.copying {
+            Some(checksum) if !self.active => checksum,
+            _ => return,
+        };
+        if (self.timer.now() - self.copied_at).to_millis() < COPY_SECTOR_MS {
+            return;
+        }
+        self.copied_at = self.timer.now();
+        // the card was swapped while the copy went on
+        if config.header.checksum() != Some(checksum) {
+            self.copying = None;
+            return;
+        }
+        let end = (self.received - self.filled as u32 + SECTOR_SIZE as u32).min(self.length);
+        let mut buf = [0u8; 512];
+        while self.received < end {
+            let wanted = buf.len().min((end - self.received) as usize);
+            match config.read_at(self.received, &mut buf[..wanted]) {
+                Ok(read) if read > 0 => self.push(&buf[..read]),
+                _ => {
+                    debug!("config could not be copied to flash");
+                    self.copying = None;
+                    return;
+                }
+            }
+        }
+        if self.received < self.length {
+            return;
+        }
+        self.copying = None;
+        if !self.commit() {
+            event!("config.bin could not be kept in flash");
+            self.rejected = Some(checksum);
+        }
+    }
+}
@@ -1,40 +1,83 @@
 use defmt::Debug2Format;
-use ssd1306::prelude::WriteOnlyDataCommand;
-use ssd1306::size::DisplaySize;
-use ssd1306::Ssd1306;
+use heapless::String;
 
-use crate::button_machine::ButtonEvent;
+use freedeck::button_machine::ButtonEvent;
+use freedeck::button_machine::PressOptions;
+
+use crate::animation::Animator;
+use crate::burn_in::BurnIn;
+use crate::burn_in::BurnInChange;
+use crate::clock::WallClock;
 use crate::config::action::ButtonFunction;
+use crate::config::action::Setting;
+use crate::config::feedback::PressFeedback;
 use crate::config::Config;
 use crate::config::RWSeek;
 use crate::debug;
-use crate::util::retry;
+use crate::display::Screens;
+use crate::event;
+use crate::event_log;
+use crate::flash::FlashUpload;
+use crate::render::Overlay;
+use crate::render::FRAME_SIZE;
+use crate::screensaver::ScreenState;
+use crate::screensaver::Screensaver;
+use crate::state::DeckState;
+use crate::BUTTON_COUNT;
+
+// bit 7 is the lowest pixel row of a display page
+const PROGRESS_BAR: u8 = 0b1100_0000;
+const ARMED_BAR: u8 = 0b1111_1111;
 
-pub struct Functions<'a, C, DI, SIZE, MODE> {
+const BRIGHTNESS_STEP: u8 = 0x20;
+
+pub struct Functions<'a, C, D> {
     config: &'a mut Config<C>,
-    display: &'a mut Ssd1306<DI, SIZE, MODE>,
+    display: &'a mut D,
     set_mux_addr: &'a mut dyn FnMut(u8),
     button_index: &'a mut usize,
+    screensaver: Screensaver<'a>,
+    animator: Animator<'a>,
+    burn_in: BurnIn<'a>,
+    // feedback of the button that is currently held
+    pressed_feedback: PressFeedback,
+    // the animation of the held button is stopped until the release
+    paused: bool,
+    // what the held button shows on top of itself, none while no button is held
+    held: Option<Overlay>,
+    config_lost: bool,
 }
 
-impl<'a, C, DI, SIZE, MODE> Functions<'a, C, DI, SIZE, MODE>
+impl<'a, C, D> Functions<'a, C, D>
 where
     C: RWSeek,
-    DI: WriteOnlyDataCommand,
-    SIZE: DisplaySize,
+    D: Screens,
 {
     pub fn new(
         config: &'a mut Config<C>,
-        display: &'a mut Ssd1306<DI, SIZE, MODE>,
+        display: &'a mut D,
         set_mux_addr: &'a mut dyn FnMut(u8),
         button_index: &'a mut usize,
+        screensaver: Screensaver<'a>,
+        mut animator: Animator<'a>,
+        burn_in: BurnIn<'a>,
     ) -> Self {
-        Self {
+        animator.start(&config.page.animations);
+        let mut functions = Self {
             config,
             display,
             set_mux_addr,
             button_index,
-        }
+            screensaver,
+            animator,
+            burn_in,
+            pressed_feedback: PressFeedback::Off,
+            paused: false,
+            held: None,
+            config_lost: false,
+        };
+        functions.apply_screen_state(ScreenState::On);
+        functions
     }
 
     fn none(&mut self) {
@@ -45,15 +88,240 @@ where
         (self.set_mux_addr)(*self.button_index as u8);
     }
 
-    fn change_page(&mut self, target_page: u16) {
-        self.config.load_page(target_page);
+    // animated buttons are left to the animator, the current one gets the overlay
+    fn draw_page(&mut self, current: Overlay) {
+        let shift = self.burn_in.shift();
         for (i, button) in self.config.page.buttons.iter().enumerate() {
+            if self.animator.is_playing(i) {
+                continue;
+            }
+            (self.set_mux_addr)(i as u8);
+            let orientation = self.config.header.orientation.then(button.orientation());
+            let overlay = match i == *self.button_index {
+                true => current,
+                false => Overlay::None,
+            };
+            self.display
+                .draw_button(button, orientation, shift, overlay);
+        }
+        if self.held.is_some() {
+            self.held = Some(current);
+        }
+        (self.set_mux_addr)(*self.button_index as u8);
+    }
+
+    // a page the file does not have is ignored, a failed read means the config is lost
+    fn change_page(&mut self, target_page: u16) {
+        if target_page >= self.config.header.page_count {
+            debug!("page {} out of range", target_page);
+            return;
+        }
+        self.config.start_loading(target_page);
+    }
+
+    // the page is read in steps so buttons and usb are served in between
+    pub fn update_loading(&mut self) {
+        match self.config.continue_loading() {
+            Ok(true) => {
+                event!("page {}", self.config.current_page() + 1);
+                self.animator.start(&self.config.page.animations);
+                self.draw_page(Overlay::None);
+                self.apply_screen_state(self.screensaver.state());
+            }
+            Ok(false) => {}
+            Err(()) => self.config_lost = true,
+        }
+    }
+
+    // draws at most one frame per call so button scans are never held up for long
+    pub fn update_animations(&mut self) {
+        if self.screensaver.is_off() {
+            return;
+        }
+        let (index, frame) = match self.animator.due() {
+            Some(due) => due,
+            None => return,
+        };
+        let mut image = [0u8; FRAME_SIZE];
+        let duration = match self.config.read_frame(index, frame, &mut image) {
+            Ok(duration) => duration,
+            Err(_) => {
+                debug!("animation of button {} unreadable", index);
+                self.animator.stop(index);
+                return;
+            }
+        };
+        let image = &image[..self.config.header.image_size() as usize - 1];
+        let button = &self.config.page.buttons[index];
+        let orientation = self.config.header.orientation.then(button.orientation());
+        (self.set_mux_addr)(index as u8);
+        self.display
+            .draw_image(image, orientation, self.burn_in.shift());
+        (self.set_mux_addr)(*self.button_index as u8);
+        self.animator.shown(index, duration);
+    }
+
+    fn apply_screen_state(&mut self, state: ScreenState) {
+        debug!("screens: {}", state);
+        for i in 0..BUTTON_COUNT {
+            (self.set_mux_addr)(i as u8);
+            match state {
+                ScreenState::On => {
+                    let brightness = self.config.header.brightness;
+                    self.display.set_contrast(brightness);
+                    self.display.set_display_on(true);
+                }
+                ScreenState::Dimmed => {
+                    let brightness = self.config.header.dim_brightness;
+                    self.display.set_contrast(brightness);
+                }
+                ScreenState::Off => self.display.set_display_on(false),
+            }
+        }
+        (self.set_mux_addr)(*self.button_index as u8);
+    }
+
+    fn wake(&mut self) {
+        if let Some(state) = self.screensaver.touch() {
+            self.apply_screen_state(state);
+        }
+    }
+
+    pub fn update_screensaver(&mut self) {
+        if let Some(state) = self.screensaver.update() {
+            self.apply_screen_state(state);
+        }
+    }
+
+    pub fn check_config(&mut self) {
+        if self.config.check().is_err() {
+            self.config_lost = true;
+        }
+    }
+
+    // events stay buffered while the card cannot take them
+    pub fn flush_log(&mut self, clock: &WallClock) {
+        let mut text = String::new();
+        let (count, dropped) = event_log::pending(clock, &mut text);
+        if !text.is_empty() && self.config.append_log(text.as_bytes()).is_ok() {
+            event_log::written(count, dropped);
+        }
+    }
+
+    pub fn read_log(&mut self, position: u32, buf: &mut [u8]) -> usize {
+        self.config.read_log(position, buf).unwrap_or(0)
+    }
+
+    // a page load goes first, the copy stalls it like everything else
+    pub fn update_flash_copy(&mut self, upload: &mut FlashUpload) {
+        if !self.config.is_loading() {
+            upload.continue_copy(self.config);
+        }
+    }
+
+    // the config has to be mounted again, e.g. after the sd card was pulled
+    pub fn config_lost(&self) -> bool {
+        self.config_lost
+    }
+
+    // leaves the screens readable for whatever is drawn next
+    pub fn reset_screens(&mut self) {
+        self.apply_screen_state(ScreenState::On);
+        for i in 0..BUTTON_COUNT {
             (self.set_mux_addr)(i as u8);
-            retry(|| self.display.draw(button.image_buff()));
+            self.display.set_invert(false);
         }
         (self.set_mux_addr)(*self.button_index as u8);
     }
 
+    pub fn update_burn_in(&mut self) {
+        if self.screensaver.is_off() {
+            return;
+        }
+        match self.burn_in.update() {
+            // the held button keeps its frame or bar and its inverted press
+            Some(BurnInChange::Shift) => self.draw_page(self.held.unwrap_or(Overlay::None)),
+            Some(BurnInChange::Invert(inverted)) => {
+                let held_inverts =
+                    self.held.is_some() && matches!(self.pressed_feedback, PressFeedback::Invert);
+                for i in 0..BUTTON_COUNT {
+                    (self.set_mux_addr)(i as u8);
+                    let pressed = held_inverts && i == *self.button_index;
+                    self.display.set_invert(inverted != pressed);
+                }
+                (self.set_mux_addr)(*self.button_index as u8);
+            }
+            None => {}
+        }
+    }
+
+    // restores dimmed screens, waking from off is up to the config
+    pub fn host_activity(&mut self) {
+        if self.config.header.host_activity_wakes() || self.screensaver.is_dimmed() {
+            self.wake();
+        }
+    }
+
+    fn change_setting(&mut self, setting: Setting) {
+        let brightness = self.config.header.brightness;
+        self.config.header.brightness = match setting {
+            Setting::DecreaseBrightness => brightness.saturating_sub(BRIGHTNESS_STEP).max(1),
+            Setting::IncreaseBrightness => brightness.saturating_add(BRIGHTNESS_STEP),
+            Setting::SetBrightness(brightness) => brightness,
+            Setting::Unknown => return,
+        };
+        self.apply_screen_state(self.screensaver.state());
+    }
+
+    // what is restored after a power cycle
+    pub fn state(&self) -> DeckState {
+        DeckState {
+            profile: self.config.header.profile_key(),
+            page: self.config.current_page(),
+            brightness: self.config.header.brightness,
+        }
+    }
+
+    fn draw_current(&mut self, overlay: Overlay) {
+        let button = &self.config.page.buttons[*self.button_index];
+        let orientation = self.config.header.orientation.then(button.orientation());
+        let shift = self.burn_in.shift();
+        self.display
+            .draw_button(button, orientation, shift, overlay);
+        if self.held.is_some() {
+            self.held = Some(overlay);
+        }
+    }
+
+    fn show_pressed(&mut self, pressed: bool) {
+        self.held = pressed.then_some(Overlay::None);
+        if pressed {
+            let button = &self.config.page.buttons[*self.button_index];
+            self.pressed_feedback = button
+                .press_feedback()
+                .unwrap_or(self.config.header.press_feedback);
+            // an animation would draw over the frame and the long press bar
+            self.paused = button.has_secondary_function()
+                || matches!(self.pressed_feedback, PressFeedback::Frame);
+            if self.paused {
+                self.animator.stop(*self.button_index);
+            }
+        }
+        match self.pressed_feedback {
+            PressFeedback::Off => {}
+            PressFeedback::Invert => {
+                let invert = pressed != self.burn_in.is_inverted();
+                self.display.set_invert(invert)
+            }
+            PressFeedback::Frame if pressed => self.draw_current(Overlay::Pressed),
+            PressFeedback::Frame => self.draw_current(Overlay::None),
+        }
+    }
+
+    fn draw_long_bar(&mut self, width: u8, pattern: u8) {
+        self.draw_current(Overlay::Bar(width, pattern));
+    }
+
     fn send_keys_down(&self, keys: &[u8]) {
         for key in keys {
             debug!("send key down: {}", key);
@@ -66,13 +334,17 @@ where
         }
     }
 
-    pub fn has_secondary_function(&self) -> bool {
+    pub fn press_options(&self) -> PressOptions {
         let button = &self.config.page.buttons[*self.button_index];
-        button.has_secondary_function()
+        PressOptions {
+            has_second_function: button.has_secondary_function(),
+            trigger_on_press: button.triggers_on_press(),
+            long_press_duration: button.long_press_duration(),
+            wake_only: self.screensaver.is_off(),
+        }
     }
 
     pub fn bar(&mut self, event: Option<ButtonEvent>) {
-        let button = &self.config.page.buttons[*self.button_index];
         let event = match event {
             Some(a) => a,
             None => {
@@ -80,11 +352,51 @@ where
                 return;
             }
         };
+        self.wake();
+        match event {
+            ButtonEvent::Wake => return,
+            // a bar still waiting for the displays is caught up by the next one
+            ButtonEvent::LongProgress(_) if !self.display.is_idle() => return,
+            ButtonEvent::LongProgress(progress) => {
+                let width = progress as u16 * self.display.size().0 as u16 / 100;
+                self.draw_long_bar(width as u8, PROGRESS_BAR);
+                return;
+            }
+            ButtonEvent::Pressed => {
+                self.show_pressed(true);
+                return;
+            }
+            ButtonEvent::Released => {
+                self.show_pressed(false);
+                if self.press_options().has_second_function {
+                    self.draw_long_bar(0, 0);
+                }
+                if self.paused {
+                    self.paused = false;
+                    self.animator.resume(*self.button_index);
+                }
+                return;
+            }
+            ButtonEvent::LongTriggered => self.draw_long_bar(self.display.size().0, ARMED_BAR),
+            _ => {}
+        }
+        let button = &self.config.page.buttons[*self.button_index];
         let function = match event {
             ButtonEvent::ShortDown | ButtonEvent::ShortUp | ButtonEvent::ShortTriggered => {
                 button.primary_function()
             }
             ButtonEvent::LongTriggered => button.secondary_function(),
+            ButtonEvent::LongProgress(_)
+            | ButtonEvent::Pressed
+            | ButtonEvent::Released
+            | ButtonEvent::Wake => return,
+        };
+        let function = match function {
+            Ok(function) => function,
+            Err(_) => {
+                debug!("button {} has malformed data", *self.button_index);
+                return;
+            }
         };
         match (function, event) {
             (
@@ -93,6 +405,12 @@ where
             ) => {
                 self.change_page(data.target_page);
             }
+            (
+                ButtonFunction::SetSetting(setting),
+                ButtonEvent::LongTriggered | ButtonEvent::ShortTriggered | ButtonEvent::ShortUp,
+            ) => {
+                self.change_setting(setting);
+            }
             (ButtonFunction::PressKeys(data), ButtonEvent::ShortDown) => {
                 self.send_keys_down(data.keys);
             }
@@ -0,0 +1,174 @@
+use core::convert::Infallible;
+use core::fmt::Write;
+
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::Alignment;
+use embedded_graphics::text::Baseline;
+use embedded_graphics::text::Text;
+use embedded_graphics::text::TextStyleBuilder;
+use heapless::String;
+
+use crate::config::action::ButtonFunction;
+use crate::config::button::Button;
+
+const LABEL_SIZE: usize = 64;
+const TEXT_PREVIEW: usize = 20;
+
+const MODIFIERS: [&str; 4] = ["Ctrl", "Shift", "Alt", "Win"];
+const SPECIAL_KEYS: [(u8, &str); 16] = [
+    (0x28, "Enter"),
+    (0x29, "Esc"),
+    (0x2a, "Bksp"),
+    (0x2b, "Tab"),
+    (0x2c, "Space"),
+    (0x46, "PrtSc"),
+    (0x49, "Ins"),
+    (0x4a, "Home"),
+    (0x4b, "PgUp"),
+    (0x4c, "Del"),
+    (0x4d, "End"),
+    (0x4e, "PgDn"),
+    (0x4f, "Right"),
+    (0x50, "Left"),
+    (0x51, "Down"),
+    (0x52, "Up"),
+];
+
+pub type Label = String<LABEL_SIZE>;
+
+// writes into an image in the same page layout the config file uses
+struct ImageTarget<'a> {
+    image: &'a mut [u8],
+    width: u32,
+    height: u32,
+}
+
+impl OriginDimensions for ImageTarget<'_> {
+    fn size(&self) -> Size {
+        Size::new(self.width, self.height)
+    }
+}
+
+impl DrawTarget for ImageTarget<'_> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0
+                || point.y < 0
+                || point.x >= self.width as i32
+                || point.y >= self.height as i32
+            {
+                continue;
+            }
+            let (x, y) = (point.x as usize, point.y as usize);
+            let index = (y / 8) * self.width as usize + x;
+            match color {
+                BinaryColor::On => self.image[index] |= 1 << (y % 8),
+                BinaryColor::Off => self.image[index] &= !(1 << (y % 8)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_key(label: &mut Label, key: u8) -> core::fmt::Result {
+    match key {
+        0x04..=0x1d => write!(label, "{}", (b'A' + key - 0x04) as char),
+        0x1e..=0x26 => write!(label, "{}", (b'1' + key - 0x1e) as char),
+        0x27 => write!(label, "0"),
+        0x3a..=0x45 => write!(label, "F{}", key - 0x39),
+        0xe0..=0xe7 => write!(label, "{}", MODIFIERS[(key & 0x03) as usize]),
+        _ => match SPECIAL_KEYS.iter().find(|(code, _)| *code == key) {
+            Some((_, name)) => write!(label, "{}", name),
+            None => write!(label, "0x{:02X}", key),
+        },
+    }
+}
+
+fn write_text(label: &mut Label, text: &[u8]) {
+    for &c in text.iter().take(TEXT_PREVIEW) {
+        if !(c.is_ascii_graphic() || c == b' ') || label.push(c as char).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn button_label(button: &Button) -> Option<Label> {
+    let mut label = Label::new();
+    if let Some(text) = button.label_text() {
+        write_text(&mut label, text);
+        return Some(label);
+    }
+    if button.has_image() {
+        return None;
+    }
+    match button.primary_function() {
+        Ok(ButtonFunction::PressKeys(data)) => {
+            for (i, key) in data.keys.iter().enumerate() {
+                if i > 0 {
+                    let _ = label.push('+');
+                }
+                let _ = write_key(&mut label, *key);
+            }
+        }
+        Ok(ButtonFunction::ChangePage(data)) => {
+            let _ = write!(label, "Page {}", u32::from(data.target_page) + 1);
+        }
+        Ok(ButtonFunction::SendText(data)) => write_text(&mut label, data.text),
+        // shown rather than a blank button, the config needs a look
+        Err(_) => {
+            let _ = label.push('?');
+        }
+        Ok(_) => return None,
+    }
+    Some(label)
+}
+
+pub fn draw_label(text: &str, width: u32, height: u32, image: &mut [u8]) {
+    image.fill(0);
+
+    let font = if text.len() as u32 * FONT_10X20.character_size.width <= width {
+        &FONT_10X20
+    } else {
+        &FONT_6X10
+    };
+    let columns = (width / font.character_size.width) as usize;
+
+    let mut wrapped: String<{ LABEL_SIZE * 2 }> = String::new();
+    let mut lines = 1;
+    for (i, c) in text.chars().enumerate() {
+        if i > 0 && i % columns == 0 {
+            let _ = wrapped.push('\n');
+            lines += 1;
+        }
+        let _ = wrapped.push(c);
+    }
+
+    let line_height = font.character_size.height as i32;
+    let position = Point::new(
+        width as i32 / 2,
+        height as i32 / 2 - (lines - 1) * line_height / 2,
+    );
+    let character_style = MonoTextStyle::new(font, BinaryColor::On);
+    let text_style = TextStyleBuilder::new()
+        .alignment(Alignment::Center)
+        .baseline(Baseline::Middle)
+        .build();
+
+    let mut target = ImageTarget {
+        image,
+        width,
+        height,
+    };
+    let _ =
+        Text::with_text_style(&wrapped, position, character_style, text_style).draw(&mut target);
+}
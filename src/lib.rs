@@ -0,0 +1,7 @@
+#![cfg_attr(not(test), no_std)]
+// hardware independent logic, also built for the host to run the tests
+pub mod button_machine;
+pub mod checksum;
+pub mod date_time;
+pub mod monotonic;
+pub mod state_log;
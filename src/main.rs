@@ -1,53 +1,177 @@
 #![no_std]
 #![no_main]
-mod button_machine;
+mod animation;
+mod burn_in;
+mod clock;
 mod config;
+mod display;
+mod event_log;
+mod flash;
 mod functions;
+mod label;
+mod msc;
 mod mux;
 mod overclock;
+mod render;
+mod screensaver;
+mod sd_bus;
 mod sdcard;
+mod serial;
+mod state;
 mod util;
 
 const BUTTON_COUNT: usize = 8;
-const SD_MHZ: u32 = 12;
 const I2C_KHZ: u32 = 800;
+const SPLASH_MS: u64 = 1500;
+const MOUNT_RETRY_MS: u64 = 1000;
+const CONFIG_CHECK_MS: u64 = 1000;
+// buttons are scanned and animations stepped once per ms
+const SCAN_US: u64 = 1000;
+const VERSION: &str = concat!("v", env!("CARGO_PKG_VERSION"));
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::ptr::addr_of_mut;
 
 use config::button::Button;
-use cortex_m::delay::Delay;
+use config::ConfigError;
+use config::ConfigFile;
 use overclock::init_clocks_and_plls;
 
+use rp_pico::entry;
 use rp_pico::hal;
+use rp_pico::hal::dma::DMAExt;
 use rp_pico::hal::gpio::DynPin;
 use rp_pico::hal::gpio::FunctionI2C;
 use rp_pico::hal::gpio::Pins;
+use rp_pico::hal::multicore::Multicore;
+use rp_pico::hal::multicore::Stack;
 use rp_pico::hal::pac;
 use rp_pico::hal::sio::Sio;
-use rp_pico::hal::timer::Timer;
 use rp_pico::hal::Clock;
 
-use sdcard::create_sdcard;
+use sd_bus::SdBus;
+use sd_bus::SpiPins;
+use sdcard::CardProbe;
 use sdcard::SDConfigFile;
-use sdcard::SpiPins;
+use sdcard::SdSpeed;
 
-use ssd1306::prelude::*;
 use ssd1306::I2CDisplayInterface;
-use ssd1306::Ssd1306;
 
-use button_machine::*;
+use freedeck::button_machine::*;
 
+use crate::animation::Animator;
+use crate::burn_in::BurnIn;
+use crate::clock::Monotonic;
+use crate::clock::SystemTimer;
+use crate::clock::WallClock;
+use crate::display::remote;
+use crate::display::remote::Remote;
+use crate::display::Screens;
+use crate::flash::FlashConfigFile;
+use crate::flash::FlashUpload;
+use crate::flash::UploadEvent;
 use crate::functions::Functions;
-use crate::mux::create_set_mux_addr;
-use crate::util::retry;
+use crate::msc::MassStorage;
+use crate::mux::init_mux;
+use crate::mux::MuxedButton;
+use crate::render::draw_missing;
+use crate::render::draw_screens;
+use crate::render::missing_displays;
+use crate::render::Overlay;
+use crate::render::NO_SHIFT;
+use crate::screensaver::Screensaver;
+use crate::serial::write_serial;
+use crate::state::StateLog;
 
-use cortex_m_rt::entry;
 use defmt_rtt as _;
+use fugit::ExtU64;
 use fugit::RateExtU32;
-use panic_probe as _;
+use heapless::String;
+use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::prelude::UsbDevice;
+use usb_device::prelude::UsbDeviceBuilder;
+use usb_device::prelude::UsbDeviceState;
+use usb_device::prelude::UsbVidPid;
+use usbd_serial::SerialPort;
+
+// composite device, the functions are told apart by interface association descriptors
+const USB_CLASS_MISC: u8 = 0xEF;
+const USB_SUBCLASS_COMMON: u8 = 0x02;
+const USB_PROTOCOL_IAD: u8 = 0x01;
+
+// in words, core 1 renders whole frames on its stack
+static mut CORE1_STACK: Stack<4096> = Stack::new();
+
+// host commands, an upload in progress takes everything until it ends or expires
+fn receive_serial(
+    upload: &mut FlashUpload,
+    clock: &WallClock,
+    msc: &mut MassStorage<'static, hal::usb::UsbBus>,
+    serial: &mut SerialPort<'static, hal::usb::UsbBus>,
+    data: &[u8],
+) {
+    if !upload.is_active() && (clock.receive(data) || msc.receive(data) || event_log::receive(data))
+    {
+        return;
+    }
+    report_upload(serial, upload.receive(data));
+}
+
+// a finished upload restarts the deck so it boots from the new config
+fn report_upload(serial: &mut SerialPort<'static, hal::usb::UsbBus>, event: Option<UploadEvent>) {
+    match event {
+        Some(UploadEvent::Started) => write_serial(serial, "upload started\r\n", false),
+        Some(UploadEvent::TooLarge) => write_serial(serial, "config too large\r\n", false),
+        Some(UploadEvent::Damaged) => write_serial(serial, "config damaged, not used\r\n", false),
+        Some(UploadEvent::Aborted) => {
+            event!("config upload aborted");
+            write_serial(serial, "upload aborted\r\n", false);
+        }
+        Some(UploadEvent::TimedOut) => {
+            event!("config upload timed out");
+            write_serial(serial, "upload timed out, aborted\r\n", false);
+        }
+        Some(UploadEvent::Done) => {
+            event!("config uploaded");
+            write_serial(serial, "upload done\r\n", true);
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        None => {}
+    }
+}
+
+// true if the host sent anything over serial
+fn poll_usb(
+    usb_dev: &mut UsbDevice<'static, hal::usb::UsbBus>,
+    serial: &mut SerialPort<'static, hal::usb::UsbBus>,
+    msc: &mut MassStorage<'static, hal::usb::UsbBus>,
+    upload: &mut FlashUpload,
+    clock: &WallClock,
+) -> bool {
+    if serial.line_coding().data_rate() == 1200 {
+        // Reset the board if the host sets the baud rate to 1200
+        hal::rom_data::reset_to_usb_boot(0, 0);
+    }
+    report_upload(serial, upload.expire());
+    if !usb_dev.poll(&mut [&mut *serial, &mut *msc]) {
+        return false;
+    }
+    let mut buf = [0u8; 64];
+    match serial.read(&mut buf) {
+        Ok(count) if count > 0 => {
+            receive_serial(upload, clock, msc, serial, &buf[..count]);
+            true
+        }
+        _ => false,
+    }
+}
 
+// the hal's entry frees the spinlocks, a reset from the panic handler can leave
+// the mux or the log buffer lock taken
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
 
     // The default is to generate a 125 MHz system clock
@@ -63,7 +187,28 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
-    let sio = Sio::new(pac.SIO);
+    let usb_bus = cortex_m::singleton!(: UsbBusAllocator<hal::usb::UsbBus> =
+        UsbBusAllocator::new(hal::usb::UsbBus::new(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            true,
+            &mut pac.RESETS,
+        ))
+    )
+    .unwrap();
+    let mut serial = SerialPort::new(usb_bus);
+    let mut msc = MassStorage::new(usb_bus);
+    let mut usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("FreeDeck")
+        .product("FreeDeck Pico")
+        .serial_number("0001")
+        .device_class(USB_CLASS_MISC)
+        .device_sub_class(USB_SUBCLASS_COMMON)
+        .device_protocol(USB_PROTOCOL_IAD)
+        .build();
+
+    let mut sio = Sio::new(pac.SIO);
 
     let pins = Pins::new(
         pac.IO_BANK0,
@@ -85,10 +230,12 @@ fn main() -> ! {
         }
     }
 
-    let delay = Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
-    let mut set_mux_addr = create_set_mux_addr(mux_pins, delay);
+    init_mux(mux_pins, clocks.system_clock.freq().to_Hz());
+    // the address the next display command goes to and the button that is read
+    let selected = Cell::new(0);
+    let mut set_mux_addr = |addr: u8| selected.set(addr);
 
-    let button_pin: DynPin = pins.gpio19.into_pull_up_input().into();
+    let button_pin = MuxedButton::new(pins.gpio19.into_pull_up_input().into(), &selected);
     let sda = pins.gpio2.into_mode::<FunctionI2C>();
     let scl = pins.gpio3.into_mode::<FunctionI2C>();
 
@@ -102,8 +249,18 @@ fn main() -> ! {
         clocks.system_clock.freq(),
     );
     let interface = I2CDisplayInterface::new(i2c);
-    let mut display = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-        .into_buffered_graphics_mode();
+
+    // core 1 renders and pushes everything to the displays, core 0 is left to buttons and usb
+    let (display_queue, display_commands) = remote::split();
+    let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+    let cores = multicore.cores();
+    cores[1]
+        .spawn(
+            unsafe { &mut (*addr_of_mut!(CORE1_STACK)).mem },
+            move || remote::serve(interface, display_commands),
+        )
+        .unwrap();
+    let mut display = Remote::new(display_queue, sio.fifo, &selected);
 
     let spi_pins = SpiPins::new(
         pins.gpio10.into(),
@@ -112,48 +269,253 @@ fn main() -> ! {
         pins.gpio9.into(),
     );
 
-    let mut sd_spi = create_sdcard(
+    let timer = SystemTimer::new(pac.TIMER, &mut pac.RESETS);
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let sd_speed = SdSpeed::new();
+    let sd_bus = SdBus::new(
         pac.SPI1,
         spi_pins,
+        (dma.ch0, dma.ch1),
         clocks.system_clock.freq(),
-        SD_MHZ,
+        &sd_speed,
+        &timer,
         &mut pac.RESETS,
     );
+    let mut sd_spi = sd_bus.card();
 
-    debug!("tick");
-    let config_file = SDConfigFile::new(&mut sd_spi);
-
-    let mut config = config::Config::new(config_file);
+    let clock = WallClock::new(&timer);
+    event_log::log_last_panic();
+    event!("boot {}", VERSION);
 
-    for (i, button) in config.page.buttons.iter().enumerate() {
+    for i in 0..BUTTON_COUNT {
         set_mux_addr(i as u8);
-        retry(|| display.init());
-        retry(|| display.draw(button.image_buff()));
+        display.init();
     }
-    debug!("tick");
-
-    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
-    let mut button_machine = ButtonMachine::new(&button_pin, 200, &timer);
-    let mut button_index = 0;
-    let mut functions = Functions::new(
-        &mut config,
+    draw_screens(
         &mut display,
         &mut set_mux_addr,
-        &mut button_index,
+        &["FreeDeck", VERSION, "loading..."],
     );
-    loop {
-        if timer.get_counter().ticks() % 1000 == 0 {
-            button_machine
-                .check_button(functions.has_secondary_function(), &mut |event| {
-                    functions.bar(event)
-                })
-                .unwrap();
+    draw_missing(&mut display, &mut set_mux_addr);
+
+    let mut missing = missing_displays(&mut display);
+    let mut speed_report = None;
+    let mut upload = FlashUpload::new(&timer);
+    let mut button_machine = ButtonMachine::new(&button_pin, 200, &timer);
+    let mut state_log = StateLog::new(&timer);
+
+    // runs until the config storage goes away, then mounts it again
+    'mount: loop {
+        // the firmware keeps its hands off the card until the host ejects it
+        if msc.wants_card() {
+            match sd_speed.acquire(&mut sd_spi, &sd_bus) {
+                Ok(card) => {
+                    draw_screens(
+                        &mut display,
+                        &mut set_mux_addr,
+                        &["USB drive", "Eject it", "to continue"],
+                    );
+                    event!("sd card lent to usb");
+                    msc.card_lent();
+                    while !msc.is_ejected() {
+                        poll_usb(&mut usb_dev, &mut serial, &mut msc, &mut upload, &clock);
+                        // a suspended bus is a host asleep or unplugged
+                        if usb_dev.state() == UsbDeviceState::Suspend {
+                            msc.host_gone();
+                        }
+                        msc.serve(Some(&card));
+                    }
+                }
+                Err(e) => {
+                    debug!("no card to lend: {:?}", defmt::Debug2Format(&e));
+                }
+            }
+            msc.card_returned();
+        }
+
+        debug!("tick");
+        let mut shown_error = None;
+        let mut retries = 0;
+        let mut config = loop {
+            // the failed attempt lets go of the card before the fallback watches it
+            let error = match SDConfigFile::new(&mut sd_spi, &sd_bus, &sd_speed, &clock)
+                .and_then(|file| config::Config::new(ConfigFile::Sd(file)))
+            {
+                Ok(mut config) => {
+                    event!("sd card at {} kHz", sd_speed.hz() / 1000);
+                    speed_report = Some(sd_speed.hz());
+                    upload.retain(&mut config);
+                    break config;
+                }
+                Err(error) => error,
+            };
+            // without a card or with a damaged file the last good copy in flash is used
+            if matches!(error, ConfigError::NoCard | ConfigError::BadChecksum) {
+                // a card inserted later, or the damaged one put back, is mounted instead
+                let missing = error == ConfigError::NoCard;
+                if let Ok(config) = FlashConfigFile::new()
+                    .map(|file| {
+                        ConfigFile::Flash(file, CardProbe::new(&mut sd_spi, &sd_speed, missing))
+                    })
+                    .and_then(config::Config::new)
+                {
+                    if error == ConfigError::BadChecksum {
+                        event!("config.bin damaged, using the copy in flash");
+                        draw_screens(
+                            &mut display,
+                            &mut set_mux_addr,
+                            &["config.bin", "damaged", "using last", "good copy"],
+                        );
+                        // the host can still reset the deck or reach the card meanwhile
+                        let shown_at = timer.now();
+                        while (timer.now() - shown_at).to_millis() < SPLASH_MS {
+                            poll_usb(&mut usb_dev, &mut serial, &mut msc, &mut upload, &clock);
+                            msc.serve_without_card();
+                        }
+                    }
+                    break config;
+                }
+            }
+            retries += 1;
+            if shown_error != Some(error) {
+                debug!("config error: {}", error);
+                event!("config error: {:?}", error);
+                draw_screens(&mut display, &mut set_mux_addr, error.message());
+                draw_missing(&mut display, &mut set_mux_addr);
+                shown_error = Some(error);
+            }
+            let failed_at = timer.now();
+            while (timer.now() - failed_at).to_millis() < MOUNT_RETRY_MS {
+                poll_usb(&mut usb_dev, &mut serial, &mut msc, &mut upload, &clock);
+                msc.serve_without_card();
+                if msc.wants_card() {
+                    continue 'mount;
+                }
+            }
+        };
+
+        if retries > 0 {
+            event!("config mounted after {} retries", retries);
+        }
+
+        if display.size().1 != config.header.display_height {
+            let height = config.header.display_height;
+            display.resize(height);
+            for i in 0..BUTTON_COUNT {
+                set_mux_addr(i as u8);
+                display.init();
+            }
+        }
+        // back to where the deck was left, as long as the profile did not change
+        if let Some(state) = state_log.restore(config.header.profile_key()) {
+            if state.page < config.header.page_count && config.load_page(state.page).is_err() {
+                continue 'mount;
+            }
+            config.header.brightness = state.brightness;
+        }
+        draw_screens(
+            &mut display,
+            &mut set_mux_addr,
+            &["FreeDeck", VERSION, config.header.profile_name()],
+        );
+        draw_missing(&mut display, &mut set_mux_addr);
+        let splash_start = timer.now();
+        while (timer.now() - splash_start).to_millis() < SPLASH_MS {
+            poll_usb(&mut usb_dev, &mut serial, &mut msc, &mut upload, &clock);
+            msc.serve_without_card();
         }
 
-        // if serial.line_coding().data_rate() == 1200 {
-        //     // Reset the board if the host sets the baud rate to 1200
-        //     hal::rom_data::reset_to_usb_boot(0, 0);
-        // }
-        // usb_dev.poll(&mut [&mut serial]);
+        for (i, button) in config.page.buttons.iter().enumerate() {
+            set_mux_addr(i as u8);
+            let orientation = config.header.orientation.then(button.orientation());
+            display.draw_button(button, orientation, NO_SHIFT, Overlay::None);
+        }
+        debug!("tick");
+
+        let mut button_index = 0;
+        let screensaver = Screensaver::new(
+            &timer,
+            config.header.screensaver_timeout as u64 * 1000,
+            config.header.dim_timeout(),
+        );
+        let burn_in = BurnIn::new(
+            &timer,
+            config.header.shift_interval as u64 * 60_000,
+            config.header.invert_interval as u64 * 60_000,
+        );
+        let mut functions = Functions::new(
+            &mut config,
+            &mut display,
+            &mut set_mux_addr,
+            &mut button_index,
+            screensaver,
+            Animator::new(&timer),
+            burn_in,
+        );
+        let mut checked_at = timer.now();
+        let mut dump = None;
+        let mut next_scan = timer.now();
+        while !functions.config_lost() && !msc.wants_card() {
+            if timer.now() >= next_scan {
+                next_scan += SCAN_US.micros();
+                button_machine
+                    .check_button(functions.press_options(), &mut |event| functions.bar(event));
+                functions.update_screensaver();
+                functions.update_animations();
+                functions.update_burn_in();
+            }
+            // a page load moves on by a block on every pass
+            functions.update_loading();
+            // as does the copy of a new config.bin into flash, by a sector now and then,
+            // never while a button is held
+            if !button_machine.is_pressed() {
+                functions.update_flash_copy(&mut upload);
+            }
+            if (timer.now() - checked_at).to_millis() >= CONFIG_CHECK_MS {
+                functions.check_config();
+                state_log.update(functions.state());
+                // the files must not rotate under a dump, the events wait in ram until it ends
+                if dump.is_none() {
+                    functions.flush_log(&clock);
+                }
+                checked_at = timer.now();
+            }
+
+            if event_log::dump_requested() {
+                dump = Some(0);
+            }
+            // a host that closed the port does not hold up logging any longer
+            if !serial.dtr() {
+                dump = None;
+            }
+            // a chunk per pass, as much as the port takes
+            if let Some(position) = dump {
+                let mut buf = [0u8; 64];
+                dump = match functions.read_log(position, &mut buf) {
+                    0 => None,
+                    read => Some(position + serial.write(&buf[..read]).unwrap_or(0) as u32),
+                };
+            }
+
+            // reported once a terminal opens the port
+            if serial.dtr() {
+                if let Some(text) = missing.take() {
+                    write_serial(&mut serial, &text, false);
+                    write_serial(&mut serial, "\r\n", false);
+                }
+                if let Some(hz) = speed_report.take() {
+                    let mut text: String<32> = String::new();
+                    let _ = write!(text, "sd card at {} kHz\r\n", hz / 1000);
+                    write_serial(&mut serial, &text, false);
+                }
+            }
+            if poll_usb(&mut usb_dev, &mut serial, &mut msc, &mut upload, &clock) {
+                functions.host_activity();
+            }
+            msc.serve_without_card();
+        }
+        debug!("config released, mounting again");
+        event!("config released, mounting again");
+        functions.reset_screens();
     }
 }
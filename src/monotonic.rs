@@ -0,0 +1,7 @@
+use fugit::Instant;
+
+pub type Micros = Instant<u64, 1, 1_000_000>;
+
+pub trait Monotonic {
+    fn now(&self) -> Micros;
+}
@@ -0,0 +1,493 @@
+use embedded_sdmmc::Block;
+use embedded_sdmmc::BlockCount;
+use embedded_sdmmc::BlockDevice;
+use embedded_sdmmc::BlockIdx;
+use usb_device::class_prelude::ControlIn;
+use usb_device::class_prelude::ControlOut;
+use usb_device::class_prelude::DescriptorWriter;
+use usb_device::class_prelude::EndpointIn;
+use usb_device::class_prelude::EndpointOut;
+use usb_device::class_prelude::InterfaceNumber;
+use usb_device::class_prelude::UsbBus;
+use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::class_prelude::UsbClass;
+use usb_device::control::Recipient;
+use usb_device::control::RequestType;
+
+use crate::debug;
+
+const PACKET_SIZE: u16 = 64;
+const BLOCK_SIZE: usize = 512;
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+const REQUEST_RESET: u8 = 0xFF;
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_SIZE: usize = 13;
+const STATUS_PASSED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_PHASE_ERROR: u8 = 2;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const VERIFY_10: u8 = 0x2F;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5A;
+
+// (sense key, additional sense code)
+const NO_SENSE: (u8, u8) = (0x00, 0x00);
+const MEDIUM_NOT_PRESENT: (u8, u8) = (0x02, 0x3A);
+const MEDIUM_ERROR: (u8, u8) = (0x03, 0x11);
+const INVALID_COMMAND: (u8, u8) = (0x05, 0x20);
+
+// sent over serial to hand the sd card to the host
+const LEND_COMMAND: [u8; 4] = *b"FDMS";
+
+const INQUIRY_DATA: [u8; 36] = *b"\x00\x80\x04\x02\x1f\x00\x00\x00FreeDeckSD Card         0001";
+
+// stands in for the card while the firmware uses it
+struct NoCard;
+
+impl BlockDevice for NoCard {
+    type Error = ();
+    fn read(&self, _blocks: &mut [Block], _start: BlockIdx, _reason: &str) -> Result<(), ()> {
+        Err(())
+    }
+    fn write(&self, _blocks: &[Block], _start: BlockIdx) -> Result<(), ()> {
+        Err(())
+    }
+    fn num_blocks(&self) -> Result<BlockCount, ()> {
+        Err(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Medium {
+    Absent,
+    Wanted,
+    Present,
+    Ejected,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    // waiting for a command block wrapper
+    Idle,
+    // sends the buffer, then the next block while any are left
+    DataIn,
+    // fills the buffer and writes it as a block, discards the data if writing failed
+    DataOut { discard: bool },
+    Status,
+}
+
+// usb bulk-only mass storage exposing the sd card while the firmware lends it to the host
+pub struct MassStorage<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    medium: Medium,
+    transfer: Transfer,
+    tag: u32,
+    expected: u32,
+    transferred: u32,
+    // the part of the data phase the command used, the rest is the residue
+    processed: u32,
+    status: u8,
+    sense: (u8, u8),
+    block: Block,
+    length: usize,
+    position: usize,
+    next_block: u32,
+    blocks_left: u32,
+}
+
+impl<'a, B: UsbBus> MassStorage<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            interface: alloc.interface(),
+            ep_in: alloc.bulk(PACKET_SIZE),
+            ep_out: alloc.bulk(PACKET_SIZE),
+            medium: Medium::Absent,
+            transfer: Transfer::Idle,
+            tag: 0,
+            expected: 0,
+            transferred: 0,
+            processed: 0,
+            status: STATUS_PASSED,
+            sense: NO_SENSE,
+            block: Block::new(),
+            length: 0,
+            position: 0,
+            next_block: 0,
+            blocks_left: 0,
+        }
+    }
+
+    // true if data was the lend command
+    pub fn receive(&mut self, data: &[u8]) -> bool {
+        if data.len() < LEND_COMMAND.len() || data[..LEND_COMMAND.len()] != LEND_COMMAND {
+            return false;
+        }
+        if self.medium == Medium::Absent {
+            self.medium = Medium::Wanted;
+        }
+        true
+    }
+
+    pub fn wants_card(&self) -> bool {
+        self.medium == Medium::Wanted
+    }
+
+    pub fn card_lent(&mut self) {
+        debug!("sd card lent to the host");
+        self.medium = Medium::Present;
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.medium == Medium::Ejected
+    }
+
+    // a host that went to sleep or away does not keep the card, same as an eject
+    pub fn host_gone(&mut self) {
+        if self.medium == Medium::Present {
+            debug!("host gone, sd card taken back");
+            self.medium = Medium::Ejected;
+        }
+    }
+
+    pub fn card_returned(&mut self) {
+        debug!("sd card back from the host");
+        self.medium = Medium::Absent;
+    }
+
+    // call after every poll of the usb device while the card is lent
+    pub fn serve<D: BlockDevice>(&mut self, device: Option<&D>) {
+        let device = device.filter(|_| self.medium == Medium::Present);
+        match self.transfer {
+            Transfer::Idle => self.read_command(device),
+            Transfer::DataIn => self.send_data(device),
+            Transfer::DataOut { discard } => self.receive_data(device, discard),
+            Transfer::Status => self.send_status(),
+        }
+    }
+
+    // the host still gets answers while the firmware uses the card
+    pub fn serve_without_card(&mut self) {
+        self.serve(None::<&NoCard>);
+    }
+
+    fn read_command<D: BlockDevice>(&mut self, device: Option<&D>) {
+        let mut cbw = [0u8; CBW_SIZE];
+        match self.ep_out.read(&mut cbw) {
+            Ok(CBW_SIZE) => {}
+            _ => return,
+        }
+        if u32::from_le_bytes(cbw[0..4].try_into().unwrap()) != CBW_SIGNATURE {
+            return;
+        }
+        self.tag = u32::from_le_bytes(cbw[4..8].try_into().unwrap());
+        self.expected = u32::from_le_bytes(cbw[8..12].try_into().unwrap());
+        self.transferred = 0;
+        self.processed = 0;
+        self.status = STATUS_PASSED;
+        self.length = 0;
+        self.position = 0;
+        self.blocks_left = 0;
+        let data_in = cbw[12] & 0x80 != 0;
+        let command: [u8; 16] = cbw[15..31].try_into().unwrap();
+
+        if Self::phase_error(&command, data_in, self.expected) {
+            debug!(
+                "scsi command {=u8:x} disagrees with the data length",
+                command[0]
+            );
+            self.status = STATUS_PHASE_ERROR;
+            return self.skip_data(data_in);
+        }
+        match self.execute(&command, device) {
+            Ok(()) if self.expected == 0 => self.transfer = Transfer::Status,
+            Ok(()) if data_in => self.transfer = Transfer::DataIn,
+            Ok(()) => self.transfer = Transfer::DataOut { discard: false },
+            Err(sense) => {
+                debug!("scsi command {=u8:x} failed", command[0]);
+                self.sense = sense;
+                self.status = STATUS_FAILED;
+                self.skip_data(data_in);
+            }
+        }
+    }
+
+    fn skip_data(&mut self, data_in: bool) {
+        self.transfer = match (self.expected, data_in) {
+            (0, _) => Transfer::Status,
+            // an empty packet ends the data phase early
+            (_, true) => Transfer::DataIn,
+            (_, false) => Transfer::DataOut { discard: true },
+        };
+    }
+
+    // the blocks must go the way the host expects and fit into what it expects,
+    // more than the blocks is fine, the residue tells the host what was left over
+    fn phase_error(command: &[u8; 16], data_in: bool, expected: u32) -> bool {
+        let reads = match command[0] {
+            READ_10 => true,
+            WRITE_10 => false,
+            _ => return false,
+        };
+        let (_, count) = Self::block_range(command);
+        let length = count * BLOCK_SIZE as u32;
+        match (length, expected) {
+            (0, _) => false,
+            (_, 0) => true,
+            _ => data_in != reads || expected < length,
+        }
+    }
+
+    fn respond(&mut self, data: &[u8]) {
+        self.length = data.len().min(self.expected as usize);
+        self.block.contents[..self.length].copy_from_slice(&data[..self.length]);
+    }
+
+    fn block_range(command: &[u8; 16]) -> (u32, u32) {
+        let lba = u32::from_be_bytes(command[2..6].try_into().unwrap());
+        let count = u16::from_be_bytes(command[7..9].try_into().unwrap());
+        (lba, count as u32)
+    }
+
+    fn execute<D: BlockDevice>(
+        &mut self,
+        command: &[u8; 16],
+        device: Option<&D>,
+    ) -> Result<(), (u8, u8)> {
+        match command[0] {
+            INQUIRY => {
+                self.respond(&INQUIRY_DATA);
+                return Ok(());
+            }
+            REQUEST_SENSE => {
+                let mut sense = [0u8; 18];
+                sense[0] = 0x70;
+                sense[2] = self.sense.0;
+                sense[7] = 10;
+                sense[12] = self.sense.1;
+                self.sense = NO_SENSE;
+                self.respond(&sense);
+                return Ok(());
+            }
+            START_STOP_UNIT => {
+                let (load_eject, start) = (command[4] & 0b10 != 0, command[4] & 0b01 != 0);
+                if load_eject && !start && self.medium == Medium::Present {
+                    self.medium = Medium::Ejected;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let device = device.ok_or(MEDIUM_NOT_PRESENT)?;
+        let BlockCount(block_count) = device.num_blocks().map_err(|_| MEDIUM_ERROR)?;
+        match command[0] {
+            TEST_UNIT_READY | PREVENT_ALLOW_REMOVAL | VERIFY_10 | SYNCHRONIZE_CACHE_10 => Ok(()),
+            READ_CAPACITY_10 => {
+                // the last block is reported, a card without blocks has none
+                let last_block = block_count.checked_sub(1).ok_or(MEDIUM_ERROR)?;
+                let mut capacity = [0u8; 8];
+                capacity[0..4].copy_from_slice(&last_block.to_be_bytes());
+                capacity[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(&capacity);
+                Ok(())
+            }
+            READ_FORMAT_CAPACITIES => {
+                let mut capacities = [0u8; 12];
+                capacities[3] = 8;
+                capacities[4..8].copy_from_slice(&block_count.to_be_bytes());
+                // formatted media, 3 byte block length
+                capacities[8] = 0x02;
+                capacities[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                self.respond(&capacities);
+                Ok(())
+            }
+            MODE_SENSE_6 => {
+                self.respond(&[3, 0, 0, 0]);
+                Ok(())
+            }
+            MODE_SENSE_10 => {
+                self.respond(&[0, 6, 0, 0, 0, 0, 0, 0]);
+                Ok(())
+            }
+            READ_10 | WRITE_10 => {
+                let (lba, count) = Self::block_range(command);
+                if lba.checked_add(count).map_or(true, |end| end > block_count) {
+                    return Err(INVALID_COMMAND);
+                }
+                self.next_block = lba;
+                self.blocks_left = count;
+                Ok(())
+            }
+            _ => Err(INVALID_COMMAND),
+        }
+    }
+
+    fn send_data<D: BlockDevice>(&mut self, device: Option<&D>) {
+        loop {
+            if self.position == self.length {
+                if self.blocks_left == 0 || self.status == STATUS_FAILED {
+                    break;
+                }
+                let device = match device {
+                    Some(device) => device,
+                    None => return self.fail(MEDIUM_NOT_PRESENT),
+                };
+                let blocks = core::slice::from_mut(&mut self.block);
+                if device
+                    .read(blocks, BlockIdx(self.next_block), "msc")
+                    .is_err()
+                {
+                    return self.fail(MEDIUM_ERROR);
+                }
+                self.next_block += 1;
+                self.blocks_left -= 1;
+                self.length = BLOCK_SIZE;
+                self.position = 0;
+            }
+            let end = self.length.min(self.position + PACKET_SIZE as usize);
+            match self.ep_in.write(&self.block.contents[self.position..end]) {
+                Ok(written) => {
+                    self.position += written;
+                    self.transferred += written as u32;
+                    self.processed += written as u32;
+                }
+                Err(_) => return,
+            }
+        }
+        if self.transferred < self.expected && self.transferred % PACKET_SIZE as u32 == 0 {
+            if self.ep_in.write(&[]).is_err() {
+                return;
+            }
+        }
+        self.transfer = Transfer::Status;
+    }
+
+    fn receive_data<D: BlockDevice>(&mut self, device: Option<&D>, discard: bool) {
+        let mut packet = [0u8; PACKET_SIZE as usize];
+        while self.transferred < self.expected {
+            let read = match self.ep_out.read(&mut packet) {
+                Ok(read) => read,
+                Err(_) => return,
+            };
+            self.transferred += read as u32;
+            if discard {
+                continue;
+            }
+            // a short packet can leave the rest for the next block
+            let mut data = &packet[..read];
+            while !data.is_empty() && self.blocks_left != 0 {
+                let taken = data.len().min(BLOCK_SIZE - self.length);
+                self.block.contents[self.length..self.length + taken]
+                    .copy_from_slice(&data[..taken]);
+                self.length += taken;
+                data = &data[taken..];
+                if self.length < BLOCK_SIZE {
+                    break;
+                }
+                self.length = 0;
+                let written = device.map(|device| {
+                    let blocks = core::slice::from_ref(&self.block);
+                    device.write(blocks, BlockIdx(self.next_block))
+                });
+                match written {
+                    Some(Ok(())) => {
+                        self.next_block += 1;
+                        self.blocks_left -= 1;
+                        self.processed += BLOCK_SIZE as u32;
+                    }
+                    Some(Err(_)) => return self.fail(MEDIUM_ERROR),
+                    None => return self.fail(MEDIUM_NOT_PRESENT),
+                }
+            }
+        }
+        self.transfer = Transfer::Status;
+    }
+
+    // the rest of the data phase is skipped, the status tells the host
+    fn fail(&mut self, sense: (u8, u8)) {
+        self.sense = sense;
+        self.status = STATUS_FAILED;
+        self.length = 0;
+        self.position = 0;
+        self.blocks_left = 0;
+        if let Transfer::DataOut { .. } = self.transfer {
+            self.transfer = Transfer::DataOut { discard: true };
+        }
+    }
+
+    fn send_status(&mut self) {
+        let mut csw = [0u8; CSW_SIZE];
+        let residue = self.expected.saturating_sub(self.processed);
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = self.status;
+        if self.ep_in.write(&csw).is_ok() {
+            self.transfer = Transfer::Idle;
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MassStorage<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.interface,
+            CLASS_MASS_STORAGE,
+            SUBCLASS_SCSI,
+            PROTOCOL_BULK_ONLY,
+        )?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.transfer = Transfer::Idle;
+        self.host_gone();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == REQUEST_GET_MAX_LUN
+        {
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == REQUEST_RESET
+        {
+            self.transfer = Transfer::Idle;
+            xfer.accept().ok();
+        }
+    }
+}
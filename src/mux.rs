@@ -1,20 +1,107 @@
 use super::hal;
-use cortex_m::delay::Delay;
+use core::cell::Cell;
+use core::ptr::addr_of_mut;
+use embedded_hal::digital::v2::InputPin;
 use embedded_hal::digital::v2::OutputPin;
 
 use hal::gpio::DynPin;
+use hal::sio::Spinlock1;
 
-pub fn create_set_mux_addr(mut mux_pins: [Option<DynPin>; 4], mut delay: Delay) -> impl FnMut(u8) {
-    move |addr| {
-        for (index, pin) in mux_pins.iter_mut().enumerate() {
+// the button input follows a switch within microseconds
+const BUTTON_SETTLE_US: u32 = 10;
+// the display bus needs longer once it is switched to another display
+const DISPLAY_SETTLE_MS: u32 = 3;
+
+// the address picks the display and the button that is read at the same time,
+// both cores switch it and the hardware spinlock keeps them apart
+struct Mux {
+    pins: [Option<DynPin>; 4],
+    button_cycles: u32,
+    display_cycles: u32,
+    selected: Option<u8>,
+    // the display the bus last talked to
+    target: Option<u8>,
+}
+
+static mut MUX: Option<Mux> = None;
+
+impl Mux {
+    // true if the address changed
+    fn select(&mut self, addr: u8) -> bool {
+        if self.selected == Some(addr) {
+            return false;
+        }
+        self.selected = Some(addr);
+        for (index, pin) in self.pins.iter_mut().enumerate() {
             if pin.is_none() {
-                return;
+                break;
             }
             match addr & (1 << index) {
                 0 => pin.as_mut().unwrap().set_low().unwrap(),
                 _ => pin.as_mut().unwrap().set_high().unwrap(),
             }
         }
-        delay.delay_ms(3_u32);
+        true
+    }
+}
+
+// before core 1 is started
+pub fn init_mux(mux_pins: [Option<DynPin>; 4], sys_hz: u32) {
+    let mux = Mux {
+        pins: mux_pins,
+        button_cycles: sys_hz / 1_000_000 * BUTTON_SETTLE_US,
+        display_cycles: sys_hz / 1000 * DISPLAY_SETTLE_MS,
+        selected: None,
+        target: None,
+    };
+    unsafe { *addr_of_mut!(MUX) = Some(mux) };
+}
+
+// runs f with the button at addr connected, the other core waits until it is done
+fn with_button<T>(addr: u8, f: impl FnOnce() -> T) -> T {
+    let _lock = Spinlock1::claim();
+    let mux = unsafe { (*addr_of_mut!(MUX)).as_mut().unwrap() };
+    if mux.select(addr) {
+        cortex_m::asm::delay(mux.button_cycles);
+    }
+    f()
+}
+
+// runs f with the display at addr on the bus, f is a whole transfer so no button
+// scan switches the mux in between, the long settle is only paid when the bus
+// goes to another display
+pub fn with_display<T>(addr: u8, f: impl FnOnce() -> T) -> T {
+    let _lock = Spinlock1::claim();
+    let mux = unsafe { (*addr_of_mut!(MUX)).as_mut().unwrap() };
+    let switched = mux.select(addr);
+    if mux.target != Some(addr) {
+        mux.target = Some(addr);
+        cortex_m::asm::delay(mux.display_cycles);
+    } else if switched {
+        cortex_m::asm::delay(mux.button_cycles);
+    }
+    f()
+}
+
+// the button behind the address that is currently scanned
+pub struct MuxedButton<'a> {
+    pin: DynPin,
+    scanned: &'a Cell<u8>,
+}
+
+impl<'a> MuxedButton<'a> {
+    pub fn new(pin: DynPin, scanned: &'a Cell<u8>) -> Self {
+        Self { pin, scanned }
+    }
+}
+
+impl InputPin for MuxedButton<'_> {
+    type Error = hal::gpio::Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        with_button(self.scanned.get(), || self.pin.is_high())
+    }
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        with_button(self.scanned.get(), || self.pin.is_low())
     }
 }
@@ -0,0 +1,256 @@
+use crate::config::button::Button;
+use crate::config::orientation::Orientation;
+use core::fmt::Write;
+
+use crate::display::presence::Present;
+use crate::display::Display;
+use crate::display::Screens;
+use crate::label::button_label;
+use crate::label::draw_label;
+use crate::label::Label;
+use crate::util::retry;
+use crate::BUTTON_COUNT;
+
+pub const FRAME_SIZE: usize = 1024;
+
+pub type Frame = [u8; FRAME_SIZE];
+
+// identifies what a display shows, None if unknown
+pub type Shown = Option<u32>;
+
+// pixels to the right and down, applied after the orientation
+pub type Shift = (i8, i8);
+pub const NO_SHIFT: Shift = (0, 0);
+
+// drawn on top of a button, the display no longer shows the plain button afterwards
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Overlay {
+    None,
+    // a border while the button is held
+    Pressed,
+    // the lowest pixel row filled with the pattern up to the width
+    Bar(u8, u8),
+}
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+fn frame_hash(orientation: Orientation, frame: &[u8]) -> u32 {
+    let (horizontal, vertical) = orientation.flips();
+    let flips = [horizontal as u8, vertical as u8];
+    flips.iter().chain(frame).fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// image is stored with swapped axes, one byte holds 8 vertical pixels
+fn transpose(image: &[u8], frame: &mut [u8], width: usize, height: usize) {
+    frame.fill(0);
+    for x in 0..width {
+        for y in 0..height {
+            let pixel = image[(x / 8) * height + y] >> (x % 8) & 1;
+            frame[(y / 8) * width + x] |= pixel << (y % 8);
+        }
+    }
+}
+
+// uncovered pixels stay dark
+fn shift_frame(frame: &mut [u8], width: usize, height: usize, (dx, dy): Shift) {
+    let mut source: Frame = [0; FRAME_SIZE];
+    let source = &mut source[..frame.len()];
+    source.copy_from_slice(frame);
+    frame.fill(0);
+    for x in 0..width {
+        for y in 0..height {
+            let source_x = x as isize - dx as isize;
+            let source_y = y as isize - dy as isize;
+            if source_x < 0 || source_y < 0 {
+                continue;
+            }
+            let (source_x, source_y) = (source_x as usize, source_y as usize);
+            if source_x >= width || source_y >= height {
+                continue;
+            }
+            let pixel = source[(source_y / 8) * width + source_x] >> (source_y % 8) & 1;
+            frame[(y / 8) * width + x] |= pixel << (y % 8);
+        }
+    }
+}
+
+pub fn render_image<'f>(
+    image: &[u8],
+    orientation: Orientation,
+    (width, height): (u8, u8),
+    shift: Shift,
+    frame: &'f mut Frame,
+) -> &'f [u8] {
+    let frame = &mut frame[..image.len()];
+    if orientation.is_transposed() {
+        transpose(image, frame, width as usize, height as usize);
+    } else {
+        frame.copy_from_slice(image);
+    }
+    if shift != NO_SHIFT {
+        shift_frame(frame, width as usize, height as usize, shift);
+    }
+    frame
+}
+
+pub fn render_button<'f>(
+    button: &Button,
+    orientation: Orientation,
+    (width, height): (u8, u8),
+    shift: Shift,
+    frame: &'f mut Frame,
+) -> &'f [u8] {
+    let mut label_image: Frame = [0; FRAME_SIZE];
+    let image = match button_label(button) {
+        Some(label) => {
+            let (label_width, label_height) = match orientation.is_transposed() {
+                false => (width as u32, height as u32),
+                true => (height as u32, width as u32),
+            };
+            let label_image = &mut label_image[..button.image_buff().len()];
+            draw_label(&label, label_width, label_height, label_image);
+            label_image
+        }
+        None => button.image_buff(),
+    };
+    render_image(image, orientation, (width, height), shift, frame)
+}
+
+pub fn set_orientation<D: Display>(display: &mut D, orientation: Orientation) {
+    let (horizontal, vertical) = orientation.flips();
+    retry(|| display.set_flips(horizontal, vertical));
+}
+
+// skips the transfer if the display already shows the same frame
+fn draw_frame<D: Display>(
+    display: &mut D,
+    frame: &[u8],
+    orientation: Orientation,
+    shown: &mut Shown,
+) {
+    let hash = frame_hash(orientation, frame);
+    if *shown == Some(hash) {
+        return;
+    }
+    set_orientation(display, orientation);
+    retry(|| display.draw(frame));
+    *shown = Some(hash);
+}
+
+fn add_border(frame: &mut [u8], width: usize, height: usize) {
+    let last_page = (height / 8 - 1) * width;
+    for column in 0..width {
+        frame[column] |= 0b0000_0001;
+        frame[last_page + column] |= 0b1000_0000;
+    }
+    for page in 0..height / 8 {
+        frame[page * width] = 0xFF;
+        frame[page * width + width - 1] = 0xFF;
+    }
+}
+
+// only the lowest page is sent, the rest of the button stays as it is
+fn draw_bar<D: Display>(display: &mut D, frame: &[u8], (bar, pattern): (u8, u8)) {
+    let (width, height) = display.size();
+    let last_page = (height as usize / 8 - 1) * width as usize;
+    let mut row = [0u8; 128];
+    let row = &mut row[..width as usize];
+    row.copy_from_slice(&frame[last_page..last_page + width as usize]);
+    for column in row.iter_mut().take(bar as usize) {
+        *column |= pattern;
+    }
+    retry(|| display.set_draw_area((0, height - 8), (width, height)));
+    retry(|| display.draw(row));
+    retry(|| display.set_draw_area((0, 0), (width, height)));
+}
+
+pub fn draw_button<D: Display>(
+    display: &mut D,
+    button: &Button,
+    orientation: Orientation,
+    shift: Shift,
+    overlay: Overlay,
+    shown: &mut Shown,
+) {
+    let (width, height) = display.size();
+    let mut frame: Frame = [0; FRAME_SIZE];
+    let len = render_button(button, orientation, (width, height), shift, &mut frame).len();
+    let frame = &mut frame[..len];
+    match overlay {
+        Overlay::None => draw_frame(display, frame, orientation, shown),
+        Overlay::Pressed => {
+            add_border(frame, width as usize, height as usize);
+            retry(|| display.draw(frame));
+            *shown = None;
+        }
+        Overlay::Bar(bar, pattern) => {
+            draw_bar(display, frame, (bar, pattern));
+            *shown = None;
+        }
+    }
+}
+
+// draws an animation frame of a button in place of its image
+pub fn draw_image<D: Display>(
+    display: &mut D,
+    image: &[u8],
+    orientation: Orientation,
+    shift: Shift,
+    shown: &mut Shown,
+) {
+    let mut frame: Frame = [0; FRAME_SIZE];
+    let image = render_image(image, orientation, display.size(), shift, &mut frame);
+    draw_frame(display, image, orientation, shown);
+}
+
+pub fn draw_text<D: Display>(display: &mut D, text: &str) {
+    let (width, height) = display.size();
+    let mut frame: Frame = [0; FRAME_SIZE];
+    let frame = &mut frame[..width as usize * height as usize / 8];
+    draw_label(text, width as u32, height as u32, frame);
+    set_orientation(display, Orientation::default());
+    retry(|| display.draw(frame));
+}
+
+// one line of text per display, the remaining displays are cleared
+pub fn draw_screens<S: Screens>(screens: &mut S, set_mux_addr: &mut dyn FnMut(u8), lines: &[&str]) {
+    for i in 0..BUTTON_COUNT {
+        set_mux_addr(i as u8);
+        screens.draw_text(lines.get(i).copied().unwrap_or(""));
+    }
+}
+
+// numbered from 1 like the buttons in the configurator
+pub fn missing_displays<S: Screens>(screens: &mut S) -> Option<Label> {
+    screens.flush();
+    let mut text = Label::new();
+    for index in Present::now().missing() {
+        let _ = write!(text, " {}", index + 1);
+    }
+    if text.is_empty() {
+        return None;
+    }
+    let mut label = Label::new();
+    let _ = write!(label, "Display{} missing", text);
+    Some(label)
+}
+
+// shown on the first present display right of the first missing one
+pub fn draw_missing<S: Screens>(screens: &mut S, set_mux_addr: &mut dyn FnMut(u8)) {
+    let text = match missing_displays(screens) {
+        Some(text) => text,
+        None => return,
+    };
+    let present = Present::now();
+    let neighbour = present
+        .missing()
+        .next()
+        .and_then(|index| present.neighbour(index));
+    if let Some(neighbour) = neighbour {
+        set_mux_addr(neighbour as u8);
+        screens.draw_text(&text);
+    }
+}
@@ -0,0 +1,79 @@
+use defmt::Format;
+
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+
+#[derive(Format, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenState {
+    On,
+    Dimmed,
+    Off,
+}
+
+pub struct Screensaver<'a> {
+    timer: &'a dyn Monotonic,
+    timeout: u64,
+    dim_timeout: u64,
+    last_activity: Micros,
+    state: ScreenState,
+}
+
+impl<'a> Screensaver<'a> {
+    // a timeout of 0 keeps the screens on forever, a dim timeout of 0 never dims
+    pub fn new(timer: &'a dyn Monotonic, timeout: u64, dim_timeout: u64) -> Self {
+        Self {
+            timer,
+            timeout,
+            dim_timeout,
+            last_activity: timer.now(),
+            state: ScreenState::On,
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.state == ScreenState::Off
+    }
+
+    pub fn state(&self) -> ScreenState {
+        self.state
+    }
+
+    pub fn is_dimmed(&self) -> bool {
+        self.state == ScreenState::Dimmed
+    }
+
+    fn idle_for(&self) -> u64 {
+        match self.timer.now().checked_duration_since(self.last_activity) {
+            Some(idle) => idle.to_millis(),
+            None => 0,
+        }
+    }
+
+    fn set_state(&mut self, state: ScreenState) -> Option<ScreenState> {
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    pub fn touch(&mut self) -> Option<ScreenState> {
+        self.last_activity = self.timer.now();
+        self.set_state(ScreenState::On)
+    }
+
+    pub fn update(&mut self) -> Option<ScreenState> {
+        if self.timeout == 0 && self.dim_timeout == 0 {
+            return None;
+        }
+        let idle = self.idle_for();
+        let state = if self.timeout != 0 && idle >= self.timeout {
+            ScreenState::Off
+        } else if self.dim_timeout != 0 && idle >= self.dim_timeout {
+            ScreenState::Dimmed
+        } else {
+            ScreenState::On
+        };
+        self.set_state(state)
+    }
+}
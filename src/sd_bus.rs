@@ -0,0 +1,437 @@
+use core::cell::Cell;
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::MODE_0;
+use embedded_sdmmc::sdmmc_proto::crc16;
+use embedded_sdmmc::sdmmc_proto::crc7;
+use embedded_sdmmc::sdmmc_proto::CMD17;
+use embedded_sdmmc::sdmmc_proto::CMD58;
+use embedded_sdmmc::sdmmc_proto::DATA_START_BLOCK;
+use embedded_sdmmc::Block;
+use embedded_sdmmc::BlockCount;
+use embedded_sdmmc::BlockDevice;
+use embedded_sdmmc::BlockIdx;
+use embedded_sdmmc::BlockSpi;
+use embedded_sdmmc::SdMmcError;
+use embedded_sdmmc::SdMmcSpi;
+use fugit::HertzU32;
+use fugit::RateExtU32;
+use rp_pico::hal::dma::bidirectional;
+use rp_pico::hal::dma::Channel;
+use rp_pico::hal::dma::CH0;
+use rp_pico::hal::dma::CH1;
+use rp_pico::hal::gpio::DynFunction;
+use rp_pico::hal::gpio::DynPin;
+use rp_pico::hal::gpio::DynPinMode;
+use rp_pico::hal::spi::Enabled;
+use rp_pico::hal::Spi;
+use rp_pico::pac;
+
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+use crate::sdcard::SdSpeed;
+
+const BLOCK_SIZE: usize = 512;
+// the card follows the block with its crc
+const BLOCK_TRANSFER: usize = BLOCK_SIZE + 2;
+// the r1 response comes within 8 bytes after a command
+const R1_TRIES: usize = 10;
+// cards have to start sending a block within 100 ms
+const TOKEN_TIMEOUT_MS: u64 = 100;
+// bytes looked at per poll while the block read ahead has not started yet
+const TOKEN_POLLS: usize = 8;
+
+pub type DmaChannels = (Channel<CH0>, Channel<CH1>);
+type CardSpi = Spi<Enabled, pac::SPI1, 8>;
+type TxBuffer = &'static [u8; BLOCK_TRANSFER];
+type RxBuffer = &'static mut [u8; BLOCK_TRANSFER];
+type BlockTransfer =
+    bidirectional::Transfer<Channel<CH0>, Channel<CH1>, TxBuffer, CardSpi, RxBuffer>;
+
+pub struct SpiPins {
+    data_pins: [DynPin; 3],
+    cs: DynPin,
+}
+
+impl SpiPins {
+    pub fn new(mosi: DynPin, miso: DynPin, sclk: DynPin, cs: DynPin) -> Self {
+        Self {
+            data_pins: [mosi, miso, sclk],
+            cs,
+        }
+    }
+}
+
+struct Hardware {
+    spi: Option<CardSpi>,
+    channels: Option<DmaChannels>,
+    buffers: Option<(TxBuffer, RxBuffer)>,
+    // the block after the last one read, the card stays selected until it is in
+    ahead: Option<Ahead>,
+}
+
+enum Ahead {
+    // the command went out, the card has not sent the start token yet
+    Waiting { address: u32, since: Micros },
+    Reading(u32, BlockTransfer),
+}
+
+// the spi bus of the card, embedded-sdmmc talks over it byte by byte and
+// block data is clocked in by dma
+pub struct SdBus<'a> {
+    hardware: RefCell<Hardware>,
+    // the last block read, files are read in pieces smaller than a block
+    fetched: RefCell<Option<(u32, [u8; BLOCK_SIZE])>>,
+    cs: RefCell<DynPin>,
+    freq: HertzU32,
+    speed: &'a SdSpeed,
+    applied: Cell<u32>,
+    timer: &'a dyn Monotonic,
+}
+
+impl<'a> SdBus<'a> {
+    pub fn new(
+        spi: pac::SPI1,
+        mut pins: SpiPins,
+        channels: DmaChannels,
+        freq: HertzU32,
+        speed: &'a SdSpeed,
+        timer: &'a dyn Monotonic,
+        reset: &mut pac::RESETS,
+    ) -> Self {
+        pins.data_pins.iter_mut().for_each(|pin| {
+            pin.try_into_mode(DynPinMode::Function(DynFunction::Spi))
+                .unwrap();
+        });
+        pins.cs.into_push_pull_output();
+
+        let spi_disabled = Spi::<_, _, 8>::new(spi);
+        let spi = spi_disabled.init(reset, freq, speed.hz().Hz(), &MODE_0);
+
+        // all ones clock the block out of the card
+        let tx = cortex_m::singleton!(: [u8; BLOCK_TRANSFER] = [0xFF; BLOCK_TRANSFER]).unwrap();
+        let rx = cortex_m::singleton!(: [u8; BLOCK_TRANSFER] = [0; BLOCK_TRANSFER]).unwrap();
+        Self {
+            hardware: RefCell::new(Hardware {
+                spi: Some(spi),
+                channels: Some(channels),
+                buffers: Some((tx, rx)),
+                ahead: None,
+            }),
+            fetched: RefCell::new(None),
+            cs: RefCell::new(pins.cs),
+            freq,
+            speed,
+            applied: Cell::new(speed.hz()),
+            timer,
+        }
+    }
+
+    // the card as embedded-sdmmc sees it
+    pub fn card(&'a self) -> SdMmcSpi<BusSpi<'a>, BusCs<'a>> {
+        SdMmcSpi::new(BusSpi { bus: self }, BusCs { bus: self })
+    }
+
+    // the clock is changed between transfers, never during one
+    fn with_spi<T>(&self, f: impl FnOnce(&mut CardSpi) -> T) -> T {
+        self.finish_ahead();
+        let mut hardware = self.hardware.borrow_mut();
+        let spi = hardware.spi.as_mut().unwrap();
+        if self.applied.get() != self.speed.hz() {
+            self.applied.set(self.speed.hz());
+            spi.set_baudrate(self.freq, self.applied.get().Hz());
+        }
+        f(spi)
+    }
+
+    fn exchange(&self, byte: u8) -> u8 {
+        let mut word = [byte];
+        self.with_spi(|spi| spi.transfer(&mut word).map(|_| ()))
+            .unwrap();
+        word[0]
+    }
+
+    // a byte of the read ahead, it belongs to a transfer so the clock stays as it is
+    fn clock_ahead(&self, byte: u8) -> u8 {
+        let mut word = [byte];
+        let mut hardware = self.hardware.borrow_mut();
+        hardware.spi.as_mut().unwrap().transfer(&mut word).unwrap();
+        word[0]
+    }
+
+    fn select(&self) {
+        self.finish_ahead();
+        self.cs.borrow_mut().set_low().unwrap();
+    }
+
+    // the card only lets go of the data line after another 8 clocks
+    fn deselect(&self) {
+        self.cs.borrow_mut().set_high().unwrap();
+        self.exchange(0xFF);
+    }
+
+    // sends a command to the selected card and returns its r1 response
+    fn command(&self, command: u8, arg: u32) -> u8 {
+        let mut frame = [0x40 | command, 0, 0, 0, 0, 0];
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = crc7(&frame[..5]);
+        self.exchange(0xFF);
+        for byte in frame {
+            self.exchange(byte);
+        }
+        (0..R1_TRIES)
+            .map(|_| self.exchange(0xFF))
+            .find(|r1| r1 & 0x80 == 0)
+            .unwrap_or(0xFF)
+    }
+
+    // clocks a block and its crc in by dma once the card sent its start token
+    fn start_block(&self) -> BlockTransfer {
+        let mut hardware = self.hardware.borrow_mut();
+        let (tx, rx) = hardware.buffers.take().unwrap();
+        let channels = hardware.channels.take().unwrap();
+        let spi = hardware.spi.take().unwrap();
+        bidirectional::Config::new(channels, tx, spi, rx).start()
+    }
+
+    fn finish_block(
+        &self,
+        transfer: BlockTransfer,
+        contents: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), SdMmcError> {
+        let (channels, tx, spi, rx) = transfer.wait();
+        let mut hardware = self.hardware.borrow_mut();
+        hardware.spi = Some(spi);
+        hardware.channels = Some(channels);
+        let (data, crc) = rx.split_at(BLOCK_SIZE);
+        let crc = u16::from_be_bytes([crc[0], crc[1]]);
+        let calculated = crc16(data);
+        contents.copy_from_slice(data);
+        hardware.buffers = Some((tx, rx));
+        match crc == calculated {
+            true => Ok(()),
+            false => Err(SdMmcError::CrcError(crc, calculated)),
+        }
+    }
+
+    // asks the selected card for a block and waits for its start token
+    fn request_block(&self, address: u32) -> Result<(), SdMmcError> {
+        if self.command(CMD17, address) != 0 {
+            return Err(SdMmcError::ReadError);
+        }
+        let since = self.timer.now();
+        let token = loop {
+            match self.exchange(0xFF) {
+                0xFF if (self.timer.now() - since).to_millis() < TOKEN_TIMEOUT_MS => {}
+                0xFF => return Err(SdMmcError::TimeoutReadBuffer),
+                token => break token,
+            }
+        };
+        if token != DATA_START_BLOCK {
+            return Err(SdMmcError::ReadError);
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, address: u32, contents: &mut [u8; BLOCK_SIZE]) -> Result<(), SdMmcError> {
+        self.request_block(address)?;
+        let transfer = self.start_block();
+        self.finish_block(transfer, contents)?;
+        *self.fetched.borrow_mut() = Some((address, *contents));
+        Ok(())
+    }
+
+    // only sends the command for the block at address, the token is polled for
+    // and the dma started by is_busy, it is picked up by the next read or bus access
+    fn read_ahead(&self, address: u32) {
+        self.select();
+        if self.command(CMD17, address) != 0 {
+            self.deselect();
+            return;
+        }
+        let since = self.timer.now();
+        self.hardware.borrow_mut().ahead = Some(Ahead::Waiting { address, since });
+    }
+
+    // looks at a few bytes for the start token of the block read ahead
+    fn poll_token(&self) {
+        let (address, since) = match self.hardware.borrow().ahead {
+            Some(Ahead::Waiting { address, since }) => (address, since),
+            _ => return,
+        };
+        let token = (0..TOKEN_POLLS)
+            .map(|_| self.clock_ahead(0xFF))
+            .find(|&byte| byte != 0xFF);
+        match token {
+            Some(DATA_START_BLOCK) => {
+                let transfer = self.start_block();
+                self.hardware.borrow_mut().ahead = Some(Ahead::Reading(address, transfer));
+            }
+            None if (self.timer.now() - since).to_millis() < TOKEN_TIMEOUT_MS => {}
+            // an error token or no answer, the read that wants the block asks again
+            _ => {
+                self.hardware.borrow_mut().ahead = None;
+                self.deselect();
+            }
+        }
+    }
+
+    // true while the block read ahead is still coming in, moves it on by a few bytes
+    pub fn is_busy(&self) -> bool {
+        self.poll_token();
+        match &self.hardware.borrow().ahead {
+            Some(Ahead::Waiting { .. }) => true,
+            Some(Ahead::Reading(_, transfer)) => !transfer.is_done(),
+            None => false,
+        }
+    }
+
+    // waits for the block read ahead and lets go of the card, a damaged block is dropped
+    // so the read that wants it goes to the card and gets the error
+    fn finish_ahead(&self) {
+        while matches!(self.hardware.borrow().ahead, Some(Ahead::Waiting { .. })) {
+            self.poll_token();
+        }
+        let ahead = self.hardware.borrow_mut().ahead.take();
+        let (address, transfer) = match ahead {
+            Some(Ahead::Reading(address, transfer)) => (address, transfer),
+            _ => return,
+        };
+        let mut contents = [0u8; BLOCK_SIZE];
+        let read = self.finish_block(transfer, &mut contents);
+        self.deselect();
+        if read.is_ok() {
+            *self.fetched.borrow_mut() = Some((address, contents));
+        }
+    }
+
+    // only a block read at the current clock counts, the clock probe reads blocks again
+    fn cached(&self, address: u32, contents: &mut [u8; BLOCK_SIZE]) -> bool {
+        self.finish_ahead();
+        if self.applied.get() != self.speed.hz() {
+            return false;
+        }
+        match &*self.fetched.borrow() {
+            Some((fetched, block)) if *fetched == address => {
+                contents.copy_from_slice(block);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // after a write or with another card the kept block may be stale
+    fn forget(&self) {
+        self.finish_ahead();
+        *self.fetched.borrow_mut() = None;
+    }
+}
+
+// embedded-sdmmc's end of the bus
+pub struct BusSpi<'a> {
+    bus: &'a SdBus<'a>,
+}
+
+impl Transfer<u8> for BusSpi<'_> {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.bus
+            .with_spi(|spi| spi.transfer(&mut *words).map(|_| ()))?;
+        Ok(words)
+    }
+}
+
+pub struct BusCs<'a> {
+    bus: &'a SdBus<'a>,
+}
+
+impl OutputPin for BusCs<'_> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.bus.select();
+        Ok(())
+    }
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.bus.cs.borrow_mut().set_high().unwrap();
+        Ok(())
+    }
+}
+
+// an initialized card whose blocks are read by dma, writes go through embedded-sdmmc
+pub struct DmaCard<'a, 'b> {
+    card: BlockSpi<'a, BusSpi<'b>, BusCs<'b>>,
+    bus: &'b SdBus<'b>,
+    high_capacity: bool,
+}
+
+impl<'a, 'b> DmaCard<'a, 'b> {
+    // asks the card whether it is addressed by block or by byte
+    pub fn new(
+        card: BlockSpi<'a, BusSpi<'b>, BusCs<'b>>,
+        bus: &'b SdBus<'b>,
+    ) -> Result<Self, SdMmcError> {
+        bus.forget();
+        bus.select();
+        let r1 = bus.command(CMD58, 0);
+        let ocr = [(); 4].map(|_| bus.exchange(0xFF));
+        bus.deselect();
+        if r1 != 0 {
+            return Err(SdMmcError::Cmd58Error);
+        }
+        Ok(Self {
+            card,
+            bus,
+            high_capacity: ocr[0] & 0x40 != 0,
+        })
+    }
+
+    fn address(&self, index: u32) -> u32 {
+        match self.high_capacity {
+            true => index,
+            false => index * BLOCK_SIZE as u32,
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.bus.is_busy()
+    }
+}
+
+impl BlockDevice for DmaCard<'_, '_> {
+    type Error = SdMmcError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut index = start_block_idx.0;
+        for block in blocks.iter_mut() {
+            let address = self.address(index);
+            index += 1;
+            if self.bus.cached(address, &mut block.contents) {
+                continue;
+            }
+            self.bus.select();
+            let read = self.bus.read_block(address, &mut block.contents);
+            self.bus.deselect();
+            read?;
+        }
+        // a file mostly goes on in the next block
+        self.bus.read_ahead(self.address(index));
+        Ok(())
+    }
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        self.bus.forget();
+        self.card.write(blocks, start_block_idx)
+    }
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        self.card.num_blocks()
+    }
+}
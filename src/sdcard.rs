@@ -1,112 +1,309 @@
-use embedded_hal::spi::MODE_0;
-use embedded_sdmmc::{Controller, File, Mode, SdMmcSpi, Volume, VolumeIdx};
-use fugit::{HertzU32, RateExtU32};
-use rp_pico::{
-    hal::{
-        gpio::{DynFunction, DynPin, DynPinMode},
-        spi::{Enabled, SpiDevice},
-        Spi,
-    },
-    pac::RESETS,
+use core::cell::Cell;
+
+use embedded_hal::blocking::spi::Transfer;
+use embedded_sdmmc::{
+    Block, BlockDevice, BlockIdx, BlockSpi, Controller, Directory, File, Mode, SdMmcError,
+    SdMmcSpi, Volume, VolumeIdx,
+};
+
+use crate::{
+    clock::WallClock,
+    config::{CardSlot, ConfigError, RWSeek},
+    debug, event,
+    sd_bus::{BusCs, BusSpi, DmaCard, SdBus},
 };
 
-use crate::{config::RWSeek, debug, util::NineTeenSeventy};
+// written in turns, once one is full the other one is started over
+const LOG_FILES: [&str; 2] = ["EVENTS0.LOG", "EVENTS1.LOG"];
+const LOG_LIMIT: u32 = 64 * 1024;
 
-pub struct SpiPins {
-    data_pins: [DynPin; 3],
-    cs: DynPin,
+// cards have to be initialized at 400 kHz, afterwards they are read at the fastest clock
+// that returns the same blocks as the init clock
+const INIT_HZ: u32 = 400_000;
+const SPEEDS_MHZ: [u32; 6] = [4, 8, 12, 16, 20, 25];
+const VERIFY_BLOCKS: usize = 4;
+
+// the spi clock for the next transfer, changed between card accesses
+pub struct SdSpeed {
+    hz: Cell<u32>,
 }
 
-impl SpiPins {
-    pub fn new(mosi: DynPin, miso: DynPin, sclk: DynPin, cs: DynPin) -> Self {
+impl SdSpeed {
+    pub fn new() -> Self {
         Self {
-            data_pins: [mosi, miso, sclk],
-            cs,
+            hz: Cell::new(INIT_HZ),
+        }
+    }
+
+    pub fn hz(&self) -> u32 {
+        self.hz.get()
+    }
+
+    fn read<D: BlockDevice>(card: &D, blocks: &mut [Block; VERIFY_BLOCKS]) -> bool {
+        card.read(blocks, BlockIdx(0), "verify").is_ok()
+    }
+
+    fn same<D: BlockDevice>(card: &D, reference: &[Block; VERIFY_BLOCKS]) -> bool {
+        let mut blocks = [Block::new(), Block::new(), Block::new(), Block::new()];
+        Self::read(card, &mut blocks)
+            && blocks
+                .iter()
+                .zip(reference.iter())
+                .all(|(block, reference)| block.contents == reference.contents)
+    }
+
+    // steps up until a read fails or differs, a timeout or crc error keeps the last good clock
+    fn negotiate<D: BlockDevice>(&self, card: &D, reference: &mut [Block; VERIFY_BLOCKS]) -> u32 {
+        if !Self::read(card, reference) {
+            return INIT_HZ;
+        }
+        let mut last_good = INIT_HZ;
+        for mhz in SPEEDS_MHZ {
+            self.hz.set(mhz * 1_000_000);
+            if !Self::same(card, reference) {
+                debug!("sd card fails at {} MHz", mhz);
+                break;
+            }
+            last_good = mhz * 1_000_000;
+        }
+        last_good
+    }
+
+    // a failed read can leave the card in the middle of a transfer, so every attempt
+    // starts over with the init handshake
+    fn init<'a, SPI, CS>(
+        &self,
+        spi_dev: &'a mut SdMmcSpi<SPI, CS>,
+    ) -> Result<BlockSpi<'a, SPI, CS>, SdMmcError>
+    where
+        SPI: Transfer<u8>,
+        CS: embedded_hal::digital::v2::OutputPin,
+        <SPI as Transfer<u8>>::Error: core::fmt::Debug,
+    {
+        self.hz.set(INIT_HZ);
+        spi_dev.acquire()
+    }
+
+    // the clock is probed with the dma reads the card is used with
+    pub fn acquire<'a, 'b>(
+        &self,
+        spi_dev: &'a mut SdMmcSpi<BusSpi<'b>, BusCs<'b>>,
+        bus: &'b SdBus<'b>,
+    ) -> Result<DmaCard<'a, 'b>, SdMmcError> {
+        let mut reference = [Block::new(), Block::new(), Block::new(), Block::new()];
+        let mut hz = self.negotiate(&DmaCard::new(self.init(spi_dev)?, bus)?, &mut reference);
+        // the clock has to read the same blocks once more after a fresh init
+        if hz != INIT_HZ {
+            let card = DmaCard::new(self.init(spi_dev)?, bus)?;
+            self.hz.set(hz);
+            if !Self::same(&card, &reference) {
+                debug!("sd card fails at {} Hz after init", hz);
+                hz = INIT_HZ;
+            }
         }
+        debug!("sd card at {} Hz", hz);
+        let card = DmaCard::new(self.init(spi_dev)?, bus)?;
+        self.hz.set(hz);
+        Ok(card)
     }
 }
 
-pub fn create_sdcard<D>(
-    spi: D,
-    mut pins: SpiPins,
-    freq: HertzU32,
-    speed: u32,
-    reset: &mut RESETS,
-) -> SdMmcSpi<Spi<Enabled, D, 8>, DynPin>
+// polls the card while the flash copy is in use, a card counts once it was missing before
+pub struct CardProbe<'a, SPI, CS>
 where
-    D: SpiDevice,
+    SPI: Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    <SPI as Transfer<u8>>::Error: core::fmt::Debug,
 {
-    pins.data_pins.iter_mut().for_each(|pin| {
-        pin.try_into_mode(DynPinMode::Function(DynFunction::Spi))
-            .unwrap();
-    });
-    pins.cs.into_push_pull_output();
+    spi_dev: &'a mut SdMmcSpi<SPI, CS>,
+    speed: &'a SdSpeed,
+    missing: bool,
+}
 
-    let spi_disabled = Spi::<_, _, 8>::new(spi);
-    let spi = spi_disabled.init(reset, freq, speed.MHz(), &MODE_0);
+impl<'a, SPI, CS> CardProbe<'a, SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    <SPI as Transfer<u8>>::Error: core::fmt::Debug,
+{
+    pub fn new(spi_dev: &'a mut SdMmcSpi<SPI, CS>, speed: &'a SdSpeed, missing: bool) -> Self {
+        Self {
+            spi_dev,
+            speed,
+            missing,
+        }
+    }
+}
 
-    SdMmcSpi::new(spi, pins.cs)
+impl<SPI, CS> CardSlot for CardProbe<'_, SPI, CS>
+where
+    SPI: Transfer<u8>,
+    CS: embedded_hal::digital::v2::OutputPin,
+    <SPI as Transfer<u8>>::Error: core::fmt::Debug,
+{
+    // only the init handshake, the clock is negotiated when the card is mounted
+    fn inserted(&mut self) -> bool {
+        let present = self.speed.init(self.spi_dev).is_ok();
+        let inserted = present && self.missing;
+        self.missing = !present;
+        inserted
+    }
 }
 
 pub struct SDConfigFile<C> {
     controller: C,
     volume: Volume,
+    root: Directory,
     file: File,
 }
 
-impl<'a, SPI, CS>
-    SDConfigFile<Controller<embedded_sdmmc::BlockSpi<'a, SPI, CS>, NineTeenSeventy, 128, 128>>
-where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-{
-    pub fn new(spi_dev: &'a mut SdMmcSpi<SPI, CS>) -> Self
-    where
-        SPI: embedded_hal::blocking::spi::Transfer<u8>,
-        CS: embedded_hal::digital::v2::OutputPin,
-        <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-    {
-        let mut controller: Controller<_, _, 128, 128> = match spi_dev.acquire() {
-            Ok(block) => Controller::new(block, NineTeenSeventy {}),
+impl<'a, 'b> SDConfigFile<Controller<DmaCard<'a, 'b>, &'a WallClock<'a>, 128, 128>> {
+    pub fn new(
+        spi_dev: &'a mut SdMmcSpi<BusSpi<'b>, BusCs<'b>>,
+        bus: &'b SdBus<'b>,
+        speed: &SdSpeed,
+        clock: &'a WallClock<'a>,
+    ) -> Result<Self, ConfigError> {
+        let mut controller: Controller<_, _, 128, 128> = match speed.acquire(spi_dev, bus) {
+            Ok(card) => Controller::new(card, clock),
             Err(e) => {
                 debug!("{:?}", defmt::Debug2Format(&e));
-                panic!("Failed to acquire SD card")
+                return Err(ConfigError::NoCard);
             }
         };
-        let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
-        let root_dir = controller.open_root_dir(&volume).unwrap();
+        let mut volume = controller
+            .get_volume(VolumeIdx(0))
+            .map_err(|_| ConfigError::NoFilesystem)?;
+        let root_dir = controller
+            .open_root_dir(&volume)
+            .map_err(|_| ConfigError::NoFilesystem)?;
         let config_file = controller
             .open_file_in_dir(&mut volume, &root_dir, "config.bin", Mode::ReadOnly)
-            .unwrap();
+            .map_err(|_| ConfigError::NoConfigFile)?;
 
-        Self {
+        Ok(Self {
             controller,
             volume,
+            root: root_dir,
             file: config_file,
-        }
+        })
+    }
+
+    fn log_size(&mut self, name: &str) -> u32 {
+        self.controller
+            .find_directory_entry(&self.volume, &self.root, name)
+            .map(|entry| entry.size)
+            .unwrap_or(0)
+    }
+
+    // modification time in the order the fields count
+    fn log_written(&mut self, name: &str) -> Option<(u8, u8, u8, u8, u8, u8)> {
+        let entry = self
+            .controller
+            .find_directory_entry(&self.volume, &self.root, name)
+            .ok()?;
+        let time = entry.mtime;
+        Some((
+            time.year_since_1970,
+            time.zero_indexed_month,
+            time.zero_indexed_day,
+            time.hours,
+            time.minutes,
+            time.seconds,
+        ))
+    }
+
+    // the file that is not full yet, with both full the one written last
+    fn active_log(&mut self) -> usize {
+        (0..LOG_FILES.len())
+            .find(|&i| self.log_size(LOG_FILES[i]) < LOG_LIMIT)
+            .unwrap_or_else(|| {
+                (0..LOG_FILES.len())
+                    .max_by_key(|&i| self.log_written(LOG_FILES[i]))
+                    .unwrap_or(0)
+            })
     }
 }
 
-impl<'a, SPI, CS> RWSeek
-    for SDConfigFile<Controller<embedded_sdmmc::BlockSpi<'a, SPI, CS>, NineTeenSeventy, 128, 128>>
-where
-    SPI: embedded_hal::blocking::spi::Transfer<u8>,
-    CS: embedded_hal::digital::v2::OutputPin,
-    <SPI as embedded_hal::blocking::spi::Transfer<u8>>::Error: core::fmt::Debug,
-{
+impl<'a> RWSeek for SDConfigFile<Controller<DmaCard<'a, '_>, &'a WallClock<'a>, 128, 128>> {
     fn seek_from_start(&mut self, pos: u32) -> Result<(), ()> {
-        self.file.seek_from_start(pos).unwrap();
-        Ok(())
+        self.file.seek_from_start(pos).map_err(|_| ())
     }
+    fn length(&self) -> u32 {
+        self.file.length()
+    }
+    fn is_busy(&mut self) -> bool {
+        self.controller.device().is_busy()
+    }
+    // fails once the card is pulled
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
-        let read = self
-            .controller
+        self.controller
             .read(&mut self.volume, &mut self.file, buf)
-            .unwrap();
-        Ok(read)
+            .map_err(|e| {
+                debug!("sd read failed: {:?}", defmt::Debug2Format(&e));
+                event!("sd read failed: {:?}", e);
+            })
     }
     fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
         todo!()
     }
+    // the file is opened per call so nothing is lost when the card is pulled
+    fn append_log(&mut self, text: &[u8]) -> Result<(), ()> {
+        let mut active = self.active_log();
+        // the older file was not deleted when the other one filled up
+        if self.log_size(LOG_FILES[active]) >= LOG_LIMIT {
+            active = LOG_FILES.len() - 1 - active;
+            self.controller
+                .delete_file_in_dir(&self.volume, &self.root, LOG_FILES[active])
+                .map_err(|_| ())?;
+        }
+        let mut file = self
+            .controller
+            .open_file_in_dir(
+                &mut self.volume,
+                &self.root,
+                LOG_FILES[active],
+                Mode::ReadWriteCreateOrAppend,
+            )
+            .map_err(|_| ())?;
+        let written = self.controller.write(&mut self.volume, &mut file, text);
+        let full = file.length() >= LOG_LIMIT;
+        self.controller
+            .close_file(&self.volume, file)
+            .map_err(|_| ())?;
+        written.map_err(|_| ())?;
+        if full {
+            let older = LOG_FILES[LOG_FILES.len() - 1 - active];
+            let _ = self
+                .controller
+                .delete_file_in_dir(&self.volume, &self.root, older);
+        }
+        Ok(())
+    }
+    fn read_log(&mut self, position: u32, buf: &mut [u8]) -> Result<usize, ()> {
+        let active = self.active_log();
+        let older = LOG_FILES.len() - 1 - active;
+        let older_size = self.log_size(LOG_FILES[older]);
+        let (name, position) = match position.checked_sub(older_size) {
+            Some(position) => (LOG_FILES[active], position),
+            None => (LOG_FILES[older], position),
+        };
+        let mut file = match self.controller.open_file_in_dir(
+            &mut self.volume,
+            &self.root,
+            name,
+            Mode::ReadOnly,
+        ) {
+            Ok(file) => file,
+            Err(_) => return Ok(0),
+        };
+        let read = match file.seek_from_start(position) {
+            Ok(()) => self.controller.read(&self.volume, &mut file, buf),
+            Err(_) => Ok(0),
+        };
+        self.controller
+            .close_file(&self.volume, file)
+            .map_err(|_| ())?;
+        read.map_err(|_| ())
+    }
 }
@@ -0,0 +1,93 @@
+use freedeck::state_log::newest;
+use freedeck::state_log::next_slot;
+use freedeck::state_log::RECORD_SIZE;
+
+pub use freedeck::state_log::DeckState;
+
+use crate::clock::Micros;
+use crate::clock::Monotonic;
+use crate::debug;
+use crate::flash::flash_bytes;
+use crate::flash::program_page;
+use crate::flash::write_sector;
+use crate::flash::PAGE_SIZE;
+use crate::flash::SECTOR_SIZE;
+use crate::flash::STATE_OFFSET;
+use crate::flash::STATE_SIZE;
+
+// a state is written once it held this long, flicking through pages wears nothing
+const SAVE_AFTER_MS: u64 = 5000;
+
+// both sectors of the log as mapped into memory
+fn log_bytes() -> &'static [u8] {
+    flash_bytes(STATE_OFFSET, STATE_SIZE as usize)
+}
+
+// records are appended, a sector is only erased once the log wraps around to it
+pub struct StateLog<'a> {
+    timer: &'a dyn Monotonic,
+    saved: Option<DeckState>,
+    pending: Option<(DeckState, Micros)>,
+    next: u32,
+    sequence: u32,
+}
+
+impl<'a> StateLog<'a> {
+    pub fn new(timer: &'a dyn Monotonic) -> Self {
+        let mut log = Self {
+            timer,
+            saved: None,
+            pending: None,
+            next: 0,
+            sequence: 0,
+        };
+        if let Some((state, sequence, next)) = newest(log_bytes()) {
+            log.saved = Some(state);
+            log.sequence = sequence;
+            log.next = next;
+        }
+        log
+    }
+
+    // the last state, if it belongs to this profile
+    pub fn restore(&self, profile: u32) -> Option<DeckState> {
+        self.saved.filter(|state| state.profile == profile)
+    }
+
+    fn write(&mut self, state: DeckState) {
+        let (offset, erase) = next_slot(log_bytes(), self.next, SECTOR_SIZE);
+        if erase {
+            write_sector(STATE_OFFSET + offset, &[0xFF; SECTOR_SIZE]);
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let page_start = offset - offset % PAGE_SIZE as u32;
+        let mut page = [0u8; PAGE_SIZE];
+        page.copy_from_slice(flash_bytes(STATE_OFFSET + page_start, PAGE_SIZE));
+        let at = (offset - page_start) as usize;
+        page[at..at + RECORD_SIZE].copy_from_slice(&state.encode(self.sequence));
+        program_page(STATE_OFFSET + page_start, &page);
+
+        self.next = offset + RECORD_SIZE as u32;
+        self.saved = Some(state);
+        debug!("state saved at {}", offset);
+    }
+
+    pub fn update(&mut self, state: DeckState) {
+        if self.saved == Some(state) {
+            self.pending = None;
+            return;
+        }
+        let since = match self.pending {
+            Some((pending, since)) if pending == state => since,
+            _ => {
+                self.pending = Some((state, self.timer.now()));
+                return;
+            }
+        };
+        if (self.timer.now() - since).to_millis() >= SAVE_AFTER_MS {
+            self.pending = None;
+            self.write(state);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::checksum::Crc32;
+
+// profile u32, page u16, brightness, reserved, sequence u32, crc32 of the first 12 bytes
+pub const RECORD_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DeckState {
+    pub profile: u32,
+    pub page: u16,
+    pub brightness: u8,
+}
+
+impl DeckState {
+    pub fn encode(&self, sequence: u32) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..4].copy_from_slice(&self.profile.to_le_bytes());
+        record[4..6].copy_from_slice(&self.page.to_le_bytes());
+        record[6] = self.brightness;
+        record[8..12].copy_from_slice(&sequence.to_le_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&record[..12]);
+        record[12..16].copy_from_slice(&crc.finish().to_le_bytes());
+        record
+    }
+
+    // erased or half written records fail the crc
+    pub fn decode(record: &[u8]) -> Option<(Self, u32)> {
+        let field = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+        let mut crc = Crc32::new();
+        crc.update(&record[..12]);
+        if crc.finish() != field(12) {
+            return None;
+        }
+        let state = Self {
+            profile: field(0),
+            page: u16::from_le_bytes([record[4], record[5]]),
+            brightness: record[6],
+        };
+        Some((state, field(8)))
+    }
+}
+
+// the sequence wraps, one up to half its range ahead is the newer
+pub fn is_newer(sequence: u32, than: u32) -> bool {
+    sequence.wrapping_sub(than) as i32 > 0
+}
+
+// the newest record of the log, with its sequence and the offset after it
+pub fn newest(log: &[u8]) -> Option<(DeckState, u32, u32)> {
+    let mut newest: Option<(DeckState, u32, u32)> = None;
+    for (i, record) in log.chunks_exact(RECORD_SIZE).enumerate() {
+        let (state, sequence) = match DeckState::decode(record) {
+            Some(decoded) => decoded,
+            None => continue,
+        };
+        if newest.is_none_or(|(_, newest, _)| is_newer(sequence, newest)) {
+            newest = Some((state, sequence, ((i + 1) * RECORD_SIZE) as u32));
+        }
+    }
+    newest
+}
+
+// where the next record goes and whether its sector is erased first,
+// a slot that is not erased moves the log on to the following sector
+pub fn next_slot(log: &[u8], next: u32, sector_size: usize) -> (u32, bool) {
+    let (size, sector) = (log.len() as u32, sector_size as u32);
+    let mut offset = next % size;
+    let erased = log[offset as usize..offset as usize + RECORD_SIZE]
+        .iter()
+        .all(|&b| b == 0xFF);
+    if !erased && !offset.is_multiple_of(sector) {
+        offset = (offset / sector + 1) * sector % size;
+    }
+    (offset, offset.is_multiple_of(sector))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR_SIZE: usize = 4 * RECORD_SIZE;
+
+    fn state(page: u16) -> DeckState {
+        DeckState {
+            profile: 0x1234_5678,
+            page,
+            brightness: 200,
+        }
+    }
+
+    // two sectors of flash, written the way the firmware does
+    struct Log {
+        flash: Vec<u8>,
+        next: u32,
+        sequence: u32,
+        erases: Vec<u32>,
+    }
+
+    impl Log {
+        fn new(sequence: u32) -> Self {
+            Self {
+                flash: vec![0xFF; 2 * SECTOR_SIZE],
+                next: 0,
+                sequence,
+                erases: Vec::new(),
+            }
+        }
+
+        fn write(&mut self, state: DeckState) -> u32 {
+            let (offset, erase) = next_slot(&self.flash, self.next, SECTOR_SIZE);
+            let at = offset as usize;
+            if erase {
+                self.flash[at..at + SECTOR_SIZE].fill(0xFF);
+                self.erases.push(offset);
+            }
+            self.sequence = self.sequence.wrapping_add(1);
+            self.flash[at..at + RECORD_SIZE].copy_from_slice(&state.encode(self.sequence));
+            self.next = offset + RECORD_SIZE as u32;
+            offset
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let record = state(7).encode(42);
+        assert_eq!(record[7], 0);
+        assert_eq!(DeckState::decode(&record), Some((state(7), 42)));
+    }
+
+    #[test]
+    fn crc_rejects_damaged_records() {
+        assert_eq!(DeckState::decode(&[0xFF; RECORD_SIZE]), None);
+        assert_eq!(DeckState::decode(&[0; RECORD_SIZE]), None);
+        let record = state(7).encode(42);
+        for at in 0..RECORD_SIZE {
+            let mut damaged = record;
+            damaged[at] ^= 0x10;
+            assert_eq!(DeckState::decode(&damaged), None, "byte {}", at);
+        }
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        assert!(is_newer(1, 0));
+        assert!(is_newer(0, u32::MAX));
+        assert!(!is_newer(u32::MAX, 0));
+        assert!(!is_newer(5, 5));
+        let mut log = vec![0xFF; 2 * SECTOR_SIZE];
+        for (slot, sequence) in [(5, u32::MAX - 1), (6, u32::MAX), (0, 0), (1, 1)] {
+            let at = slot * RECORD_SIZE;
+            log[at..at + RECORD_SIZE].copy_from_slice(&state(slot as u16).encode(sequence));
+        }
+        assert_eq!(newest(&log), Some((state(1), 1, 2 * RECORD_SIZE as u32)));
+    }
+
+    #[test]
+    fn empty_log() {
+        assert_eq!(newest(&[0xFF; 2 * SECTOR_SIZE]), None);
+    }
+
+    #[test]
+    fn rotates_between_sectors() {
+        let mut log = Log::new(u32::MAX - 5);
+        let offsets: Vec<u32> = (0..10).map(|page| log.write(state(page))).collect();
+        let sector = SECTOR_SIZE as u32;
+        let record = RECORD_SIZE as u32;
+        let expected: Vec<u32> = (0..10).map(|i| i * record % (2 * sector)).collect();
+        assert_eq!(offsets, expected);
+        assert_eq!(log.erases, [0, sector, 0]);
+        assert_eq!(newest(&log.flash), Some((state(9), 4, 2 * record)));
+        // the second sector is still there to fall back on
+        assert!(DeckState::decode(&log.flash[SECTOR_SIZE..SECTOR_SIZE + RECORD_SIZE]).is_some());
+    }
+
+    #[test]
+    fn written_slot_moves_to_the_next_sector() {
+        let mut log = Log::new(0);
+        log.write(state(0));
+        log.flash[RECORD_SIZE] = 0;
+        assert_eq!(log.write(state(1)), SECTOR_SIZE as u32);
+        assert_eq!(
+            newest(&log.flash),
+            Some((state(1), 2, (SECTOR_SIZE + RECORD_SIZE) as u32))
+        );
+    }
+
+    #[test]
+    fn picks_up_after_the_newest() {
+        let mut log = Log::new(0);
+        for page in 0..6 {
+            log.write(state(page));
+        }
+        let (_, sequence, next) = newest(&log.flash).unwrap();
+        let mut restarted = Log {
+            next,
+            sequence,
+            ..Log::new(0)
+        };
+        restarted.flash = log.flash.clone();
+        assert_eq!(restarted.write(state(6)), 6 * RECORD_SIZE as u32);
+        assert_eq!(
+            newest(&restarted.flash),
+            Some((state(6), 7, 7 * RECORD_SIZE as u32))
+        );
+    }
+}
@@ -1,4 +1,3 @@
-use embedded_sdmmc::TimeSource;
 #[macro_export]
 macro_rules! debug {
     ($($all:tt)*) => {
@@ -7,20 +6,6 @@ macro_rules! debug {
     };
 }
 
-pub struct NineTeenSeventy {}
-impl TimeSource for NineTeenSeventy {
-    fn get_timestamp(&self) -> embedded_sdmmc::Timestamp {
-        embedded_sdmmc::Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
-    }
-}
-
 pub fn retry<F, T, E>(mut f: F) -> T
 where
     F: FnMut() -> Result<T, E>,